@@ -0,0 +1,521 @@
+//! SASL mechanisms for use with `IMAPClient::authenticate`.
+//!
+//! Any type implementing `Authenticator` can be used, so mechanisms other than the ones provided
+//! here can be plugged in.
+
+use base64;
+use imaperror::IMAPError;
+use openssl::crypto::hash::{self, Type};
+use openssl::crypto::hmac::hmac;
+use rand::{self, Rng};
+
+/// A SASL mechanism, as used by the `AUTHENTICATE` command (RFC 3501 section 6.2.2).
+pub trait Authenticator {
+    /// The name of the mechanism, e.g. `PLAIN`.
+    fn mechanism(&self) -> &str;
+
+    /// Computes the response to a challenge from the server.
+    ///
+    /// `challenge` has already been base64 decoded, and the returned response will be base64
+    /// encoded before it is sent. Returning an error cancels the exchange.
+    fn process(&mut self, challenge: &[u8]) -> Result<Vec<u8>, IMAPError>;
+
+    /// Called once the server has accepted the exchange. Mechanisms that authenticate the server
+    /// as well use this to fail if it never proved its identity.
+    fn complete(&mut self) -> Result<(), IMAPError> {
+        Ok(())
+    }
+}
+
+/// The `PLAIN` mechanism from RFC 4616.
+///
+/// The password is sent in clear, so this should only be used over an encrypted connection.
+#[derive(Debug)]
+pub struct Plain {
+    authzid: String,
+    username: String,
+    password: String,
+}
+
+impl Plain {
+    pub fn new<IntoString: Into<String>>(username: IntoString, password: IntoString) -> Plain {
+        Plain {
+            authzid: String::new(),
+            username: username.into(),
+            password: password.into(),
+        }
+    }
+
+    /// Acts as the user `authzid` once authenticated, if the server allows it.
+    pub fn with_authzid<IntoString: Into<String>>(username: IntoString,
+                                                  password: IntoString,
+                                                  authzid: IntoString)
+                                                  -> Plain {
+        Plain {
+            authzid: authzid.into(),
+            username: username.into(),
+            password: password.into(),
+        }
+    }
+}
+
+impl Authenticator for Plain {
+    fn mechanism(&self) -> &str {
+        "PLAIN"
+    }
+
+    fn process(&mut self, _: &[u8]) -> Result<Vec<u8>, IMAPError> {
+        Ok(format!("{}\0{}\0{}", self.authzid, self.username, self.password).into_bytes())
+    }
+}
+
+/// The obsolete but widely deployed `LOGIN` mechanism, which asks for the username and then the
+/// password.
+#[derive(Debug)]
+pub struct Login {
+    username: String,
+    password: String,
+    step: u32,
+}
+
+impl Login {
+    pub fn new<IntoString: Into<String>>(username: IntoString, password: IntoString) -> Login {
+        Login {
+            username: username.into(),
+            password: password.into(),
+            step: 0,
+        }
+    }
+}
+
+impl Authenticator for Login {
+    fn mechanism(&self) -> &str {
+        "LOGIN"
+    }
+
+    fn process(&mut self, _: &[u8]) -> Result<Vec<u8>, IMAPError> {
+        self.step += 1;
+        match self.step {
+            1 => Ok(self.username.as_bytes().to_vec()),
+            2 => Ok(self.password.as_bytes().to_vec()),
+            _ => Err(IMAPError::LoginError("Unexpected challenge for LOGIN".to_owned())),
+        }
+    }
+}
+
+/// Google's and Microsoft's `XOAUTH2` mechanism, which authenticates with an OAuth 2.0 access
+/// token.
+#[derive(Debug)]
+pub struct XOAuth2 {
+    user: String,
+    access_token: String,
+    error: Option<Vec<u8>>,
+}
+
+impl XOAuth2 {
+    pub fn new<IntoString: Into<String>>(user: IntoString, access_token: IntoString) -> XOAuth2 {
+        XOAuth2 {
+            user: user.into(),
+            access_token: access_token.into(),
+            error: None,
+        }
+    }
+
+    /// The error details the server sent if it rejected the token, usually a JSON object.
+    pub fn error(&self) -> Option<&[u8]> {
+        self.error.as_ref().map(|error| &error[..])
+    }
+}
+
+impl Authenticator for XOAuth2 {
+    fn mechanism(&self) -> &str {
+        "XOAUTH2"
+    }
+
+    fn process(&mut self, challenge: &[u8]) -> Result<Vec<u8>, IMAPError> {
+        // A challenge after the token has been sent carries the error, and must be answered
+        // with an empty response for the server to fail the command.
+        if !challenge.is_empty() {
+            self.error = Some(challenge.to_vec());
+            return Ok(Vec::new());
+        }
+
+        Ok(format!("user={}\x01auth=Bearer {}\x01\x01", self.user, self.access_token)
+               .into_bytes())
+    }
+}
+
+/// The `OAUTHBEARER` mechanism from RFC 7628, the standardised form of `XOAUTH2`.
+#[derive(Debug)]
+pub struct OAuthBearer {
+    user: String,
+    host: Option<(String, u32)>,
+    access_token: String,
+    error: Option<Vec<u8>>,
+}
+
+impl OAuthBearer {
+    pub fn new<IntoString: Into<String>>(user: IntoString,
+                                         access_token: IntoString)
+                                         -> OAuthBearer {
+        OAuthBearer {
+            user: user.into(),
+            host: None,
+            access_token: access_token.into(),
+            error: None,
+        }
+    }
+
+    /// Also sends the host and port that were connected to, which some servers require.
+    pub fn with_host<IntoString: Into<String>>(user: IntoString,
+                                               access_token: IntoString,
+                                               host: IntoString,
+                                               port: u32)
+                                               -> OAuthBearer {
+        OAuthBearer {
+            user: user.into(),
+            host: Some((host.into(), port)),
+            access_token: access_token.into(),
+            error: None,
+        }
+    }
+
+    /// The error details the server sent if it rejected the token, a JSON object.
+    pub fn error(&self) -> Option<&[u8]> {
+        self.error.as_ref().map(|error| &error[..])
+    }
+}
+
+impl Authenticator for OAuthBearer {
+    fn mechanism(&self) -> &str {
+        "OAUTHBEARER"
+    }
+
+    fn process(&mut self, challenge: &[u8]) -> Result<Vec<u8>, IMAPError> {
+        // As with XOAUTH2, a later challenge carries the error and is answered with a lone ^A
+        if !challenge.is_empty() {
+            self.error = Some(challenge.to_vec());
+            return Ok(b"\x01".to_vec());
+        }
+
+        let user = escape_saslname(&self.user);
+        let host = match self.host {
+            Some((ref host, port)) => format!("host={}\x01port={}\x01", host, port),
+            None => String::new(),
+        };
+
+        Ok(format!("n,a={},\x01{}auth=Bearer {}\x01\x01", user, host, self.access_token)
+               .into_bytes())
+    }
+}
+
+/// The `CRAM-MD5` mechanism from RFC 2195.
+///
+/// Only a keyed digest of the password is sent, but the server is not authenticated in return.
+#[derive(Debug)]
+pub struct CramMd5 {
+    username: String,
+    password: String,
+}
+
+impl CramMd5 {
+    pub fn new<IntoString: Into<String>>(username: IntoString, password: IntoString) -> CramMd5 {
+        CramMd5 {
+            username: username.into(),
+            password: password.into(),
+        }
+    }
+}
+
+impl Authenticator for CramMd5 {
+    fn mechanism(&self) -> &str {
+        "CRAM-MD5"
+    }
+
+    fn process(&mut self, challenge: &[u8]) -> Result<Vec<u8>, IMAPError> {
+        let digest = hmac(Type::MD5, self.password.as_bytes(), challenge);
+        let digest: Vec<String> = digest.iter().map(|b| format!("{:02x}", b)).collect();
+
+        Ok(format!("{} {}", self.username, digest.concat()).into_bytes())
+    }
+}
+
+/// The most `i=` iterations a SCRAM server may ask for. Each costs an HMAC, so this bounds how
+/// long a hostile server can keep the client busy; servers typically ask for 4096.
+const MAX_SCRAM_ITERATIONS: u32 = 100000;
+
+/// The `SCRAM-SHA-1` and `SCRAM-SHA-256` mechanisms from RFC 5802 and RFC 7677.
+///
+/// The password is never sent, and the server has to prove it knows it too: if the server's
+/// signature is wrong or missing, authentication fails with `IMAPError::ServerAuthError`.
+///
+/// The username and password are used as given, without SASLprep normalisation.
+pub struct Scram {
+    mechanism: &'static str,
+    hash: Type,
+    username: String,
+    password: String,
+    client_nonce: String,
+    client_first_bare: String,
+    server_signature: Vec<u8>,
+    step: u32,
+    verified: bool,
+}
+
+impl Scram {
+    pub fn sha1<IntoString: Into<String>>(username: IntoString, password: IntoString) -> Scram {
+        Scram::new("SCRAM-SHA-1", Type::SHA1, username.into(), password.into())
+    }
+
+    pub fn sha256<IntoString: Into<String>>(username: IntoString, password: IntoString) -> Scram {
+        Scram::new("SCRAM-SHA-256", Type::SHA256, username.into(), password.into())
+    }
+
+    fn new(mechanism: &'static str, hash: Type, username: String, password: String) -> Scram {
+        let nonce: String = rand::thread_rng().gen_ascii_chars().take(24).collect();
+
+        Scram {
+            mechanism: mechanism,
+            hash: hash,
+            username: username,
+            password: password,
+            client_nonce: nonce,
+            client_first_bare: String::new(),
+            server_signature: Vec::new(),
+            step: 0,
+            verified: false,
+        }
+    }
+
+    /// `client-first-message`
+    fn client_first(&mut self) -> Vec<u8> {
+        self.client_first_bare = format!("n={},r={}",
+                                         escape_saslname(&self.username),
+                                         self.client_nonce);
+        format!("n,,{}", self.client_first_bare).into_bytes()
+    }
+
+    /// Answers `server-first-message` with `client-final-message`.
+    fn client_final(&mut self, server_first: &[u8]) -> Result<Vec<u8>, IMAPError> {
+        let server_first = String::from_utf8_lossy(server_first).into_owned();
+        let invalid = || IMAPError::ServerAuthError(format!("Invalid SCRAM challenge: {}", server_first));
+
+        let mut nonce = None;
+        let mut salt = None;
+        let mut iterations = None;
+        for attribute in server_first.split(',') {
+            if attribute.starts_with("r=") {
+                nonce = Some(&attribute[2..]);
+            } else if attribute.starts_with("s=") {
+                salt = base64::decode(attribute[2..].as_bytes());
+            } else if attribute.starts_with("i=") {
+                iterations = attribute[2..].parse::<u32>().ok();
+            } else if attribute.starts_with("m=") {
+                // Mandatory extensions we don't know about
+                return Err(invalid());
+            }
+        }
+
+        let nonce = try!(nonce.ok_or_else(&invalid));
+        let salt = try!(salt.ok_or_else(&invalid));
+        let iterations = try!(iterations.ok_or_else(&invalid));
+
+        // The server extends our nonce; anything else means the exchange is being replayed
+        if !nonce.starts_with(&self.client_nonce) || nonce.len() == self.client_nonce.len() ||
+           iterations == 0 {
+            return Err(invalid());
+        }
+        if iterations > MAX_SCRAM_ITERATIONS {
+            return Err(IMAPError::ServerAuthError(format!("Server asked for {} SCRAM iterations, \
+                                                           more than the {} allowed",
+                                                          iterations,
+                                                          MAX_SCRAM_ITERATIONS)));
+        }
+
+        let salted_password = hi(self.hash, self.password.as_bytes(), &salt, iterations);
+        let client_key = hmac(self.hash, &salted_password, b"Client Key");
+        let stored_key = hash::hash(self.hash, &client_key);
+        let server_key = hmac(self.hash, &salted_password, b"Server Key");
+
+        // "biws" is the base64 encoded GS2 header "n,,"
+        let client_final_without_proof = format!("c=biws,r={}", nonce);
+        let auth_message = format!("{},{},{}",
+                                   self.client_first_bare,
+                                   server_first,
+                                   client_final_without_proof);
+
+        let client_signature = hmac(self.hash, &stored_key, auth_message.as_bytes());
+        let client_proof: Vec<u8> = client_key.iter()
+                                              .zip(&client_signature)
+                                              .map(|(k, s)| k ^ s)
+                                              .collect();
+        self.server_signature = hmac(self.hash, &server_key, auth_message.as_bytes());
+
+        Ok(format!("{},p={}",
+                   client_final_without_proof,
+                   base64::encode(&client_proof))
+               .into_bytes())
+    }
+
+    /// Checks the server signature in `server-final-message`.
+    fn verify(&mut self, server_final: &[u8]) -> Result<Vec<u8>, IMAPError> {
+        let server_final = String::from_utf8_lossy(server_final).into_owned();
+
+        if server_final.starts_with("e=") {
+            return Err(IMAPError::LoginError(format!("Server rejected authentication: {}",
+                                                     &server_final[2..])));
+        }
+
+        let signature = if server_final.starts_with("v=") {
+            base64::decode(server_final[2..].split(',').next().unwrap_or("").as_bytes())
+        } else {
+            None
+        };
+
+        match signature {
+            Some(ref signature) if constant_time_eq(signature, &self.server_signature) => {
+                self.verified = true;
+                Ok(Vec::new())
+            }
+            _ => Err(IMAPError::ServerAuthError("Server signature does not match".to_owned())),
+        }
+    }
+}
+
+impl Authenticator for Scram {
+    fn mechanism(&self) -> &str {
+        self.mechanism
+    }
+
+    fn process(&mut self, challenge: &[u8]) -> Result<Vec<u8>, IMAPError> {
+        self.step += 1;
+        match self.step {
+            1 => Ok(self.client_first()),
+            2 => self.client_final(challenge),
+            3 => self.verify(challenge),
+            _ => Err(IMAPError::ServerAuthError("Unexpected challenge after SCRAM exchange"
+                                                    .to_owned())),
+        }
+    }
+
+    fn complete(&mut self) -> Result<(), IMAPError> {
+        if self.verified {
+            Ok(())
+        } else {
+            Err(IMAPError::ServerAuthError("Server accepted authentication without sending its \
+                                            signature"
+                                               .to_owned()))
+        }
+    }
+}
+
+/// The `Hi` function from RFC 5802, which is PBKDF2 with HMAC and a single output block.
+fn hi(hash: Type, password: &[u8], salt: &[u8], iterations: u32) -> Vec<u8> {
+    let mut block = salt.to_vec();
+    block.extend_from_slice(&[0, 0, 0, 1]);
+
+    let mut u = hmac(hash, password, &block);
+    let mut result = u.clone();
+
+    for _ in 1..iterations {
+        u = hmac(hash, password, &u);
+        for (r, b) in result.iter_mut().zip(&u) {
+            *r ^= *b;
+        }
+    }
+
+    result
+}
+
+/// Compares two byte strings in time independent of where they differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// ',' and '=' are not allowed as is in SASL user names.
+fn escape_saslname(name: &str) -> String {
+    name.replace("=", "=3D").replace(",", "=2C")
+}
+
+#[cfg(test)]
+mod tests {
+    use imaperror::IMAPError;
+    use super::{Authenticator, Scram};
+
+    /// Runs the exchange with `client_nonce` in place of a random one, checking each message
+    /// against the ones given.
+    fn exchange(mut scram: Scram,
+                client_nonce: &str,
+                client_first: &str,
+                server_first: &str,
+                client_final: &str,
+                server_final: &str) {
+        scram.client_nonce = client_nonce.to_owned();
+        assert_eq!(scram.process(b"").unwrap(), client_first.as_bytes());
+        assert_eq!(scram.process(server_first.as_bytes()).unwrap(), client_final.as_bytes());
+        assert!(scram.complete().is_err());
+        assert_eq!(scram.process(server_final.as_bytes()).unwrap(), b"");
+        assert!(scram.complete().is_ok());
+    }
+
+    #[test]
+    fn scram_sha1() {
+        // The example of section 5 of RFC 5802
+        exchange(Scram::sha1("user", "pencil"),
+                 "fyko+d2lbbFgONRv9qkxdawL",
+                 "n,,n=user,r=fyko+d2lbbFgONRv9qkxdawL",
+                 "r=fyko+d2lbbFgONRv9qkxdawL3rfcNHYJY1ZVvWVs7j,s=QSXCR+Q6sek8bf92,i=4096",
+                 "c=biws,r=fyko+d2lbbFgONRv9qkxdawL3rfcNHYJY1ZVvWVs7j,\
+                  p=v0X8v3Bz2T0CJGbJQyF0X+HI4Ts=",
+                 "v=rmF9pqV8S7suAoZWja4dJRkFsKQ=");
+    }
+
+    #[test]
+    fn scram_sha256() {
+        // The example of section 3 of RFC 7677
+        exchange(Scram::sha256("user", "pencil"),
+                 "rOprNGfwEbeRWgbNEkqO",
+                 "n,,n=user,r=rOprNGfwEbeRWgbNEkqO",
+                 "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                  s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096",
+                 "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                  p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=",
+                 "v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=");
+    }
+
+    #[test]
+    fn scram_rejects_wrong_server_signature() {
+        let mut scram = Scram::sha1("user", "pencil");
+        scram.client_nonce = "fyko+d2lbbFgONRv9qkxdawL".to_owned();
+        scram.process(b"").unwrap();
+        scram.process(b"r=fyko+d2lbbFgONRv9qkxdawL3rfcNHYJY1ZVvWVs7j,s=QSXCR+Q6sek8bf92,i=4096")
+             .unwrap();
+
+        match scram.process(b"v=AAAAAAAAAAAAAAAAAAAAAAAAAAA=") {
+            Err(IMAPError::ServerAuthError(_)) => {}
+            result => panic!("{:?}", result),
+        }
+        assert!(scram.complete().is_err());
+    }
+
+    #[test]
+    fn scram_rejects_invalid_challenges() {
+        for challenge in &["r=fyko+d2lbbFgONRv9qkxdawL,s=QSXCR+Q6sek8bf92,i=4096",
+                           "r=other3rfcNHYJY1ZVvWVs7j,s=QSXCR+Q6sek8bf92,i=4096",
+                           "r=fyko+d2lbbFgONRv9qkxdawL3rfc,s=QSXCR+Q6sek8bf92,i=0",
+                           "r=fyko+d2lbbFgONRv9qkxdawL3rfc,s=QSXCR+Q6sek8bf92,i=4294967295",
+                           "r=fyko+d2lbbFgONRv9qkxdawL3rfc,i=4096",
+                           "m=ext,r=fyko+d2lbbFgONRv9qkxdawL3rfc,s=QSXCR+Q6sek8bf92,i=4096"] {
+            let mut scram = Scram::sha1("user", "pencil");
+            scram.client_nonce = "fyko+d2lbbFgONRv9qkxdawL".to_owned();
+            scram.process(b"").unwrap();
+            match scram.process(challenge.as_bytes()) {
+                Err(IMAPError::ServerAuthError(_)) => {}
+                result => panic!("{}: {:?}", challenge, result),
+            }
+        }
+    }
+}
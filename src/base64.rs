@@ -0,0 +1,98 @@
+//! The base64 encoding from RFC 4648, as used to exchange SASL data.
+
+const ALPHABET: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub fn encode(input: &[u8]) -> String {
+    let mut output = String::with_capacity((input.len() + 2) / 3 * 4);
+
+    for chunk in input.chunks(3) {
+        let b = [chunk[0],
+                 if chunk.len() > 1 { chunk[1] } else { 0 },
+                 if chunk.len() > 2 { chunk[2] } else { 0 }];
+        let n = (b[0] as usize) << 16 | (b[1] as usize) << 8 | b[2] as usize;
+
+        output.push(ALPHABET[n >> 18 & 63] as char);
+        output.push(ALPHABET[n >> 12 & 63] as char);
+        output.push(if chunk.len() > 1 { ALPHABET[n >> 6 & 63] as char } else { '=' });
+        output.push(if chunk.len() > 2 { ALPHABET[n & 63] as char } else { '=' });
+    }
+
+    output
+}
+
+/// Decodes `input`, returning `None` if it is not valid base64.
+pub fn decode(input: &[u8]) -> Option<Vec<u8>> {
+    if input.len() % 4 != 0 {
+        return None;
+    }
+
+    let mut output = Vec::with_capacity(input.len() / 4 * 3);
+
+    for (i, chunk) in input.chunks(4).enumerate() {
+        let last = i == input.len() / 4 - 1;
+        let padding = chunk.iter().rev().take_while(|&&b| b == b'=').count();
+        if padding > 2 || (padding > 0 && !last) {
+            return None;
+        }
+
+        let mut n = 0;
+        for &b in &chunk[..4 - padding] {
+            n = n << 6 | try_opt!(value(b));
+        }
+        n <<= 6 * padding;
+
+        output.push((n >> 16) as u8);
+        if padding < 2 {
+            output.push((n >> 8) as u8);
+        }
+        if padding < 1 {
+            output.push(n as u8);
+        }
+    }
+
+    Some(output)
+}
+
+fn value(b: u8) -> Option<u32> {
+    match b {
+        b'A'..=b'Z' => Some((b - b'A') as u32),
+        b'a'..=b'z' => Some((b - b'a') as u32 + 26),
+        b'0'..=b'9' => Some((b - b'0') as u32 + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode};
+
+    // The test vectors of section 10 of RFC 4648
+    const VECTORS: &'static [(&'static str, &'static str)] = &[("", ""),
+                                                               ("f", "Zg=="),
+                                                               ("fo", "Zm8="),
+                                                               ("foo", "Zm9v"),
+                                                               ("foob", "Zm9vYg=="),
+                                                               ("fooba", "Zm9vYmE="),
+                                                               ("foobar", "Zm9vYmFy")];
+
+    #[test]
+    fn round_trip() {
+        for &(plain, encoded) in VECTORS {
+            assert_eq!(encode(plain.as_bytes()), encoded);
+            assert_eq!(decode(encoded.as_bytes()).unwrap(), plain.as_bytes());
+        }
+
+        let bytes: Vec<u8> = (0..256).map(|b| b as u8).collect();
+        assert_eq!(decode(encode(&bytes).as_bytes()).unwrap(), bytes);
+    }
+
+    #[test]
+    fn invalid() {
+        assert_eq!(decode(b"Zm9"), None);
+        assert_eq!(decode(b"Zm9v!A=="), None);
+        assert_eq!(decode(b"Zg==Zg=="), None);
+        assert_eq!(decode(b"Z==="), None);
+    }
+}
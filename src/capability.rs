@@ -0,0 +1,113 @@
+//! The capabilities a server advertises, as returned by `CAPABILITY` or the `CAPABILITY`
+//! response code.
+
+use std::collections::HashSet;
+use std::collections::hash_set::Iter;
+use std::fmt;
+use std::iter::FromIterator;
+
+/// A single capability. Capability names are case-insensitive, so they are compared upper-cased.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Capability {
+    Imap4rev1,
+    StartTls,
+    LoginDisabled,
+    Idle,
+    Move,
+    UidPlus,
+    Unselect,
+    /// `AUTH=<mechanism>`, a supported SASL mechanism.
+    Auth(String),
+    /// Any other capability, by its upper-cased name.
+    Other(String),
+}
+
+impl<'a> From<&'a str> for Capability {
+    fn from(name: &str) -> Capability {
+        let name = name.to_uppercase();
+        match &name[..] {
+            "IMAP4REV1" => Capability::Imap4rev1,
+            "STARTTLS" => Capability::StartTls,
+            "LOGINDISABLED" => Capability::LoginDisabled,
+            "IDLE" => Capability::Idle,
+            "MOVE" => Capability::Move,
+            "UIDPLUS" => Capability::UidPlus,
+            "UNSELECT" => Capability::Unselect,
+            _ if name.starts_with("AUTH=") => Capability::Auth(name[5..].to_owned()),
+            _ => Capability::Other(name),
+        }
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Capability::Imap4rev1 => write!(f, "IMAP4rev1"),
+            Capability::StartTls => write!(f, "STARTTLS"),
+            Capability::LoginDisabled => write!(f, "LOGINDISABLED"),
+            Capability::Idle => write!(f, "IDLE"),
+            Capability::Move => write!(f, "MOVE"),
+            Capability::UidPlus => write!(f, "UIDPLUS"),
+            Capability::Unselect => write!(f, "UNSELECT"),
+            Capability::Auth(ref mechanism) => write!(f, "AUTH={}", mechanism),
+            Capability::Other(ref name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// The set of capabilities advertised by a server.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Capabilities {
+    capabilities: HashSet<Capability>,
+}
+
+impl Capabilities {
+    pub fn has(&self, capability: &Capability) -> bool {
+        self.capabilities.contains(capability)
+    }
+
+    /// Checks for a capability by name, e.g. `"IDLE"` or `"AUTH=PLAIN"`.
+    pub fn has_str(&self, name: &str) -> bool {
+        self.has(&Capability::from(name))
+    }
+
+    /// Checks whether the SASL mechanism `mechanism` is advertised as `AUTH=<mechanism>`.
+    pub fn has_auth(&self, mechanism: &str) -> bool {
+        self.has(&Capability::Auth(mechanism.to_uppercase()))
+    }
+
+    /// The advertised SASL mechanisms.
+    pub fn auth_mechanisms(&self) -> Vec<&str> {
+        self.capabilities
+            .iter()
+            .filter_map(|capability| match *capability {
+                Capability::Auth(ref mechanism) => Some(&mechanism[..]),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn iter(&self) -> Iter<Capability> {
+        self.capabilities.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.capabilities.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.capabilities.is_empty()
+    }
+}
+
+impl<'a> FromIterator<&'a str> for Capabilities {
+    fn from_iter<I: IntoIterator<Item = &'a str>>(iter: I) -> Capabilities {
+        Capabilities { capabilities: iter.into_iter().map(Capability::from).collect() }
+    }
+}
+
+impl<'a> FromIterator<&'a String> for Capabilities {
+    fn from_iter<I: IntoIterator<Item = &'a String>>(iter: I) -> Capabilities {
+        iter.into_iter().map(|name| &name[..]).collect()
+    }
+}
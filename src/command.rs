@@ -0,0 +1,156 @@
+/// One piece of a command: either text sent as is, or the contents of a literal that has to be
+/// announced first and may only be sent once the server asks for it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Part {
+    Text(Vec<u8>),
+    Literal(Vec<u8>),
+}
+
+/// A command to be sent to the server, without its tag.
+///
+/// Arguments given to `arg` are encoded as an atom, a quoted string or a literal, whichever is
+/// the simplest form that transmits them unchanged. This keeps values such as passwords and
+/// mailbox names from breaking, or injecting anything into, the command line.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Command {
+    parts: Vec<Part>,
+}
+
+impl Command {
+    pub fn new(name: &str) -> Command {
+        Command { parts: vec![Part::Text(name.as_bytes().to_vec())] }
+    }
+
+    /// Appends an argument, encoded as an atom, quoted string or literal as needed.
+    pub fn arg<A: AsRef<[u8]>>(mut self, arg: A) -> Command {
+        let arg = arg.as_ref();
+        self.separate();
+
+        if !arg.is_empty() && arg.iter().all(|&b| is_atom_char(b)) {
+            self.push_text(arg);
+        } else if arg.iter().all(|&b| is_quotable(b)) {
+            let mut quoted = Vec::with_capacity(arg.len() + 2);
+            quoted.push(b'"');
+            for &b in arg {
+                if b == b'"' || b == b'\\' {
+                    quoted.push(b'\\');
+                }
+                quoted.push(b);
+            }
+            quoted.push(b'"');
+            self.push_text(&quoted);
+        } else {
+            self.parts.push(Part::Literal(arg.to_vec()));
+        }
+
+        self
+    }
+
+    /// Appends an argument as a literal, however simple it is.
+    pub fn literal<A: AsRef<[u8]>>(mut self, arg: A) -> Command {
+        self.separate();
+        self.parts.push(Part::Literal(arg.as_ref().to_vec()));
+        self
+    }
+
+    /// Appends an argument that is already in its wire form, such as a sequence set or a list of
+    /// fetch attributes.
+    pub fn raw(mut self, raw: &str) -> Command {
+        self.separate();
+        self.push_text(raw.as_bytes());
+        self
+    }
+
+    /// Opens a parenthesised list. The argument that follows is not separated from it by a space.
+    pub fn open_list(mut self) -> Command {
+        self.separate();
+        self.push_text(b"(");
+        self
+    }
+
+    pub fn close_list(mut self) -> Command {
+        self.push_text(b")");
+        self
+    }
+
+    pub fn parts(&self) -> &[Part] {
+        &self.parts
+    }
+
+    /// Adds the space before an argument, unless it is the first in a list.
+    fn separate(&mut self) {
+        if let Some(&Part::Text(ref last)) = self.parts.last() {
+            if last.ends_with(b"(") {
+                return;
+            }
+        }
+        self.push_text(b" ");
+    }
+
+    fn push_text(&mut self, text: &[u8]) {
+        if let Some(&mut Part::Text(ref mut last)) = self.parts.last_mut() {
+            last.extend_from_slice(text);
+            return;
+        }
+        self.parts.push(Part::Text(text.to_vec()));
+    }
+}
+
+/// `ATOM-CHAR`: any printable ASCII character except the atom specials.
+pub fn is_atom_char(b: u8) -> bool {
+    match b {
+        b'(' | b')' | b'{' | b' ' | b'%' | b'*' | b'"' | b'\\' | b']' => false,
+        0x21..=0x7e => true,
+        _ => false,
+    }
+}
+
+/// Anything but NUL, CR, LF and 8-bit characters can go in a quoted string.
+fn is_quotable(b: u8) -> bool {
+    match b {
+        b'\r' | b'\n' | 0 => false,
+        _ => b < 0x80,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Command, Part};
+
+    fn encode(arg: &[u8]) -> Vec<Part> {
+        Command::new("X").arg(arg).parts().to_vec()
+    }
+
+    #[test]
+    fn atoms() {
+        assert_eq!(encode(b"INBOX"), [Part::Text(b"X INBOX".to_vec())]);
+        assert_eq!(encode(b"a+b-c.d/e"), [Part::Text(b"X a+b-c.d/e".to_vec())]);
+    }
+
+    #[test]
+    fn quoted_strings() {
+        assert_eq!(encode(b""), [Part::Text(b"X \"\"".to_vec())]);
+        assert_eq!(encode(b"two words"), [Part::Text(b"X \"two words\"".to_vec())]);
+        assert_eq!(encode(b"say \"hi\""), [Part::Text(b"X \"say \\\"hi\\\"\"".to_vec())]);
+        assert_eq!(encode(b"a\\b"), [Part::Text(b"X \"a\\\\b\"".to_vec())]);
+
+        // Atom specials are never sent bare
+        for arg in &["a]b", "a(b", "a)b", "a{b", "a%b", "a*b"] {
+            assert_eq!(encode(arg.as_bytes()),
+                       [Part::Text(format!("X \"{}\"", arg).into_bytes())]);
+        }
+    }
+
+    #[test]
+    fn literals() {
+        for arg in &[&b"a\rb"[..], b"a\nb", b"a\0b", b"caf\xc3\xa9", b"\xff"] {
+            assert_eq!(encode(arg), [Part::Text(b"X ".to_vec()), Part::Literal(arg.to_vec())]);
+        }
+
+        let cmd = Command::new("LOGIN").arg("caf\u{e9}").arg("secret");
+        assert_eq!(cmd.parts(),
+                   [Part::Text(b"LOGIN ".to_vec()),
+                    Part::Literal(b"caf\xc3\xa9".to_vec()),
+                    Part::Text(b" secret".to_vec())]);
+    }
+}
@@ -0,0 +1,203 @@
+//! Dates and times in the forms IMAP uses for searching and for the internal date of messages.
+
+use std::fmt;
+
+const MONTHS: [&'static str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep",
+                                    "Oct", "Nov", "Dec"];
+
+/// A calendar date, as compared against by the date search keys. The server ignores the time
+/// and timezone of messages when comparing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Date {
+    year: u16,
+    month: u8,
+    day: u8,
+}
+
+impl Date {
+    /// Returns `None` if the year, month (`1` to `12`) or day do not make up a real date.
+    pub fn new(year: u16, month: u8, day: u8) -> Option<Date> {
+        let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+        let days = match month {
+            1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+            4 | 6 | 9 | 11 => 30,
+            2 if leap => 29,
+            2 => 28,
+            _ => return None,
+        };
+
+        if year == 0 || year > 9999 || day == 0 || day > days {
+            return None;
+        }
+
+        Some(Date {
+            year: year,
+            month: month,
+            day: day,
+        })
+    }
+
+    pub fn year(&self) -> u16 {
+        self.year
+    }
+
+    pub fn month(&self) -> u8 {
+        self.month
+    }
+
+    pub fn day(&self) -> u8 {
+        self.day
+    }
+}
+
+/// Formats the date as IMAP expects it, e.g. `1-Feb-1994`.
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}-{:04}", self.day, MONTHS[self.month as usize - 1], self.year)
+    }
+}
+
+/// A date and time in a given timezone, as used for the internal date of a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    date: Date,
+    hour: u8,
+    minute: u8,
+    second: u8,
+    offset: i16,
+}
+
+impl DateTime {
+    /// `offset` is the timezone's offset from UTC in minutes, e.g. `-420` for `-0700`. Returns
+    /// `None` if the time or offset is out of range.
+    pub fn new(date: Date, hour: u8, minute: u8, second: u8, offset: i16) -> Option<DateTime> {
+        // A second of 60 allows for leap seconds
+        if hour > 23 || minute > 59 || second > 60 || offset <= -24 * 60 || offset >= 24 * 60 {
+            return None;
+        }
+
+        Some(DateTime {
+            date: date,
+            hour: hour,
+            minute: minute,
+            second: second,
+            offset: offset,
+        })
+    }
+
+    pub fn date(&self) -> Date {
+        self.date
+    }
+
+    pub fn hour(&self) -> u8 {
+        self.hour
+    }
+
+    pub fn minute(&self) -> u8 {
+        self.minute
+    }
+
+    pub fn second(&self) -> u8 {
+        self.second
+    }
+
+    /// The offset from UTC in minutes.
+    pub fn offset(&self) -> i16 {
+        self.offset
+    }
+
+    /// Parses a date and time in the form the server sends them, e.g. `17-Jul-1996 02:44:25
+    /// -0700`. Returns `None` if it is not in that form.
+    pub fn parse(date_time: &str) -> Option<DateTime> {
+        // Days before the 10th may be padded with a space
+        let mut parts = date_time.trim_left().split(' ');
+        let date = try_opt!(parts.next());
+        let time = try_opt!(parts.next());
+        let zone = try_opt!(parts.next());
+        if parts.next().is_some() {
+            return None;
+        }
+
+        let mut date = date.split('-');
+        let day = try_opt!(try_opt!(date.next()).parse().ok());
+        let month = try_opt!(date.next()).to_lowercase();
+        let month = try_opt!(MONTHS.iter().position(|m| m.to_lowercase() == month)) as u8 + 1;
+        let year = try_opt!(try_opt!(date.next()).parse().ok());
+
+        let mut time = time.split(':');
+        let hour = try_opt!(try_opt!(time.next()).parse().ok());
+        let minute = try_opt!(try_opt!(time.next()).parse().ok());
+        let second = try_opt!(try_opt!(time.next()).parse().ok());
+
+        let zone = zone.as_bytes();
+        if zone.len() != 5 || !zone[1..].iter().all(|&b| b >= b'0' && b <= b'9') {
+            return None;
+        }
+        let digit = |i: usize| (zone[i] - b'0') as i16;
+        let minutes = (digit(1) * 10 + digit(2)) * 60 + digit(3) * 10 + digit(4);
+        let offset = match zone[0] {
+            b'+' => minutes,
+            b'-' => -minutes,
+            _ => return None,
+        };
+
+        DateTime::new(try_opt!(Date::new(year, month, day)), hour, minute, second, offset)
+    }
+}
+
+/// Formats the date and time as IMAP expects it, e.g. `17-Jul-1996 02:44:25 -0700`.
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.offset < 0 { '-' } else { '+' };
+        let offset = self.offset.abs();
+
+        write!(f,
+               "{:02}-{}-{:04} {:02}:{:02}:{:02} {}{:02}{:02}",
+               self.date.day,
+               MONTHS[self.date.month as usize - 1],
+               self.date.year,
+               self.hour,
+               self.minute,
+               self.second,
+               sign,
+               offset / 60,
+               offset % 60)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Date, DateTime};
+
+    #[test]
+    fn dates() {
+        assert_eq!(Date::new(1994, 2, 1).unwrap().to_string(), "1-Feb-1994");
+        assert!(Date::new(2000, 2, 29).is_some());
+        assert_eq!(Date::new(1900, 2, 29), None);
+        assert_eq!(Date::new(1994, 13, 1), None);
+    }
+
+    #[test]
+    fn round_trip() {
+        let date_time = DateTime::parse("17-Jul-1996 02:44:25 -0700").unwrap();
+        assert_eq!(date_time.date(), Date::new(1996, 7, 17).unwrap());
+        assert_eq!((date_time.hour(), date_time.minute(), date_time.second()), (2, 44, 25));
+        assert_eq!(date_time.offset(), -7 * 60);
+        assert_eq!(date_time.to_string(), "17-Jul-1996 02:44:25 -0700");
+
+        let date_time = DateTime::parse(" 1-Feb-2016 23:05:00 +0530").unwrap();
+        assert_eq!(date_time.offset(), 5 * 60 + 30);
+        assert_eq!(date_time.to_string(), "01-Feb-2016 23:05:00 +0530");
+        assert_eq!(DateTime::parse(&date_time.to_string()), Some(date_time));
+    }
+
+    #[test]
+    fn invalid() {
+        assert_eq!(DateTime::parse("17-Jul-1996 02:44:25"), None);
+        assert_eq!(DateTime::parse("17-Jux-1996 02:44:25 -0700"), None);
+        assert_eq!(DateTime::parse("31-Feb-1996 02:44:25 -0700"), None);
+        assert_eq!(DateTime::parse("17-Jul-1996 24:00:00 -0700"), None);
+        assert_eq!(DateTime::parse("17-Jul-1996 02:44:25 0700"), None);
+        assert_eq!(DateTime::parse("17-Jul-1996 02:44:25 -0700 extra"), None);
+    }
+}
@@ -0,0 +1,1204 @@
+//! The data returned by `FETCH`, parsed into typed values.
+
+use command::Command;
+use date::DateTime;
+use flag::Flag;
+use imaperror::IMAPError;
+use response::{self, Response, UntaggedResponse, Value};
+use std::cmp;
+use std::fmt;
+use std::io::{self, Read};
+use std::mem;
+use stream::{self, Stream};
+use {DataItem, IMAPClient, Macro, Mailbox, SequenceSet, UidSet};
+
+/// An address from an envelope. Each part is `None` if the server sent `NIL`.
+///
+/// RFC 2822 group syntax is represented as in RFC 3501: the start of a group is an address with
+/// only `mailbox` set, to the group name, and the end of it is an address with nothing set.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Address {
+    /// The display name, still encoded as in the message if it uses RFC 2047 encoded words.
+    pub name: Option<String>,
+    /// The obsolete source route.
+    pub route: Option<String>,
+    pub mailbox: Option<String>,
+    pub host: Option<String>,
+}
+
+impl Address {
+    fn parse(value: &Value) -> Option<Address> {
+        let parts = try_opt!(value.as_list());
+        if parts.len() != 4 {
+            return None;
+        }
+
+        Some(Address {
+            name: parts[0].as_string(),
+            route: parts[1].as_string(),
+            mailbox: parts[2].as_string(),
+            host: parts[3].as_string(),
+        })
+    }
+}
+
+/// The envelope of a message: the parsed fields of its header that identify it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Envelope {
+    /// The `Date:` header, as written in the message.
+    pub date: Option<String>,
+    pub subject: Option<String>,
+    pub from: Vec<Address>,
+    pub sender: Vec<Address>,
+    pub reply_to: Vec<Address>,
+    pub to: Vec<Address>,
+    pub cc: Vec<Address>,
+    pub bcc: Vec<Address>,
+    pub in_reply_to: Option<String>,
+    pub message_id: Option<String>,
+}
+
+impl Envelope {
+    fn parse(value: &Value) -> Option<Envelope> {
+        let fields = try_opt!(value.as_list());
+        if fields.len() != 10 {
+            return None;
+        }
+
+        Some(Envelope {
+            date: fields[0].as_string(),
+            subject: fields[1].as_string(),
+            from: try_opt!(Envelope::addresses(&fields[2])),
+            sender: try_opt!(Envelope::addresses(&fields[3])),
+            reply_to: try_opt!(Envelope::addresses(&fields[4])),
+            to: try_opt!(Envelope::addresses(&fields[5])),
+            cc: try_opt!(Envelope::addresses(&fields[6])),
+            bcc: try_opt!(Envelope::addresses(&fields[7])),
+            in_reply_to: fields[8].as_string(),
+            message_id: fields[9].as_string(),
+        })
+    }
+
+    fn addresses(value: &Value) -> Option<Vec<Address>> {
+        if value.is_nil() {
+            return Some(Vec::new());
+        }
+
+        let mut addresses = Vec::new();
+        for address in try_opt!(value.as_list()) {
+            addresses.push(try_opt!(Address::parse(address)));
+        }
+        Some(addresses)
+    }
+}
+
+/// The `Content-Disposition` of a body part, such as `attachment` with a `filename` parameter.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Disposition {
+    /// The disposition type, lower-cased.
+    pub kind: String,
+    /// The parameters, with their names lower-cased.
+    pub params: Vec<(String, String)>,
+}
+
+/// The MIME structure of a message, as returned for `BODY` and `BODYSTRUCTURE`.
+///
+/// Each node is one body part. A multipart has its parts in `parts` and no size or encoding; a
+/// `message/rfc822` part has the envelope and structure of the message it contains. The fields
+/// after `lines` are extension data, which only `BODYSTRUCTURE` returns.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BodyStructure {
+    /// The media type, such as `text` or `multipart`, lower-cased.
+    pub media_type: String,
+    /// The media subtype, such as `plain` or `mixed`, lower-cased.
+    pub subtype: String,
+    /// The `Content-Type` parameters, such as `charset`, with their names lower-cased.
+    pub params: Vec<(String, String)>,
+    /// The `Content-ID`.
+    pub id: Option<String>,
+    /// The `Content-Description`.
+    pub description: Option<String>,
+    /// The `Content-Transfer-Encoding`, lower-cased.
+    pub encoding: Option<String>,
+    /// The size of the encoded part in octets.
+    pub size: Option<u32>,
+    /// The size of `text` and `message/rfc822` parts in lines.
+    pub lines: Option<u32>,
+    pub md5: Option<String>,
+    pub disposition: Option<Disposition>,
+    pub language: Vec<String>,
+    pub location: Option<String>,
+    /// The envelope of a `message/rfc822` part.
+    pub envelope: Option<Envelope>,
+    /// The structure of the message a `message/rfc822` part contains.
+    pub message: Option<Box<BodyStructure>>,
+    /// The parts of a multipart.
+    pub parts: Vec<BodyStructure>,
+    section: String,
+}
+
+impl BodyStructure {
+    /// The section of this part, such as `1.2.3`, to fetch it by with `BODY[1.2.3]`.
+    ///
+    /// A multipart has the section of the message it is the body of, which is empty for the
+    /// message itself. A message that is not multipart has its body in section `1`.
+    pub fn section(&self) -> &str {
+        &self.section
+    }
+
+    pub fn is_multipart(&self) -> bool {
+        !self.parts.is_empty()
+    }
+
+    /// Finds the part with the given section. For an empty section, this is the part for the
+    /// body of the whole message.
+    pub fn find(&self, section: &str) -> Option<&BodyStructure> {
+        if self.section == section {
+            return Some(self);
+        }
+        if let Some(ref message) = self.message {
+            if let Some(part) = message.find(section) {
+                return Some(part);
+            }
+        }
+        self.parts.iter().filter_map(|part| part.find(section)).next()
+    }
+
+    fn parse(value: &Value) -> Option<BodyStructure> {
+        BodyStructure::parse_part(value, "")
+    }
+
+    /// Parses the body of the message with section `parent`.
+    fn parse_part(value: &Value, parent: &str) -> Option<BodyStructure> {
+        let fields = try_opt!(value.as_list());
+        if fields.first().and_then(Value::as_list).is_some() {
+            BodyStructure::parse_multipart(fields, parent)
+        } else if parent.is_empty() {
+            BodyStructure::parse_single(fields, "1".to_owned())
+        } else {
+            BodyStructure::parse_single(fields, format!("{}.1", parent))
+        }
+    }
+
+    /// `1*body SP media-subtype [SP body-ext-mpart]`
+    fn parse_multipart(fields: &[Value], section: &str) -> Option<BodyStructure> {
+        let mut parts = Vec::new();
+        for field in fields.iter().take_while(|field| field.as_list().is_some()) {
+            let child = if section.is_empty() {
+                (parts.len() + 1).to_string()
+            } else {
+                format!("{}.{}", section, parts.len() + 1)
+            };
+            let list = try_opt!(field.as_list());
+            let part = if list.first().and_then(Value::as_list).is_some() {
+                BodyStructure::parse_multipart(list, &child)
+            } else {
+                BodyStructure::parse_single(list, child)
+            };
+            parts.push(try_opt!(part));
+        }
+
+        let rest = &fields[parts.len()..];
+        let subtype = try_opt!(rest.first().and_then(Value::as_string)).to_lowercase();
+        let mut body = BodyStructure::empty("multipart".to_owned(), subtype, section.to_owned());
+        body.parts = parts;
+        if rest.len() > 1 {
+            body.params = BodyStructure::params(&rest[1]);
+            body.extension(&rest[2..]);
+        }
+        Some(body)
+    }
+
+    /// `media-type SP media-subtype SP body-fields [...] [SP body-ext-1part]`
+    fn parse_single(fields: &[Value], section: String) -> Option<BodyStructure> {
+        if fields.len() < 7 {
+            return None;
+        }
+
+        let mut body = BodyStructure::empty(try_opt!(fields[0].as_string()).to_lowercase(),
+                                            try_opt!(fields[1].as_string()).to_lowercase(),
+                                            section);
+        body.params = BodyStructure::params(&fields[2]);
+        body.id = fields[3].as_string();
+        body.description = fields[4].as_string();
+        body.encoding = fields[5].as_string().map(|encoding| encoding.to_lowercase());
+        body.size = fields[6].as_number().map(|n| n as u32);
+
+        let is_message = body.media_type == "message" && body.subtype == "rfc822";
+        let rest = if is_message && fields.len() >= 10 {
+            body.envelope = Envelope::parse(&fields[7]);
+            body.message = BodyStructure::parse_part(&fields[8], &body.section).map(Box::new);
+            body.lines = fields[9].as_number().map(|n| n as u32);
+            &fields[10..]
+        } else if body.media_type == "text" && fields.len() >= 8 {
+            body.lines = fields[7].as_number().map(|n| n as u32);
+            &fields[8..]
+        } else {
+            &fields[7..]
+        };
+
+        if !rest.is_empty() {
+            body.md5 = rest[0].as_string();
+            body.extension(&rest[1..]);
+        }
+        Some(body)
+    }
+
+    fn empty(media_type: String, subtype: String, section: String) -> BodyStructure {
+        BodyStructure {
+            media_type: media_type,
+            subtype: subtype,
+            params: Vec::new(),
+            id: None,
+            description: None,
+            encoding: None,
+            size: None,
+            lines: None,
+            md5: None,
+            disposition: None,
+            language: Vec::new(),
+            location: None,
+            envelope: None,
+            message: None,
+            parts: Vec::new(),
+            section: section,
+        }
+    }
+
+    /// The disposition, language and location shared by the extension data of every part.
+    fn extension(&mut self, fields: &[Value]) {
+        let mut fields = fields.iter();
+
+        if let Some(disposition) = fields.next().and_then(Value::as_list) {
+            if let Some(kind) = disposition.first().and_then(Value::as_string) {
+                self.disposition = Some(Disposition {
+                    kind: kind.to_lowercase(),
+                    params: disposition.get(1).map_or(Vec::new(), BodyStructure::params),
+                });
+            }
+        }
+
+        if let Some(language) = fields.next() {
+            self.language = match language.as_list() {
+                Some(list) => list.iter().filter_map(Value::as_string).collect(),
+                None => language.as_string().into_iter().collect(),
+            };
+        }
+
+        self.location = fields.next().and_then(Value::as_string);
+    }
+
+    /// A list of attribute/value pairs, or `NIL` for none.
+    fn params(value: &Value) -> Vec<(String, String)> {
+        let list = value.as_list().unwrap_or(&[]);
+        list.chunks(2)
+            .filter_map(|pair| {
+                match (pair[0].as_string(), pair.get(1).and_then(Value::as_string)) {
+                    (Some(name), Some(value)) => Some((name.to_lowercase(), value)),
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+}
+
+/// A data item to fetch, for `DataItem::Attributes`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Attribute {
+    Uid,
+    Flags,
+    Envelope,
+    InternalDate,
+    /// `RFC822.SIZE`.
+    Size,
+    /// The body structure without extension data.
+    Body,
+    BodyStructure,
+    /// The contents of a body section, or part of them.
+    Section(BodySection),
+}
+
+impl fmt::Display for Attribute {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Attribute::Uid => write!(f, "UID"),
+            Attribute::Flags => write!(f, "FLAGS"),
+            Attribute::Envelope => write!(f, "ENVELOPE"),
+            Attribute::InternalDate => write!(f, "INTERNALDATE"),
+            Attribute::Size => write!(f, "RFC822.SIZE"),
+            Attribute::Body => write!(f, "BODY"),
+            Attribute::BodyStructure => write!(f, "BODYSTRUCTURE"),
+            Attribute::Section(ref section) => write!(f, "{}", section),
+        }
+    }
+}
+
+/// What of a message or body part a `BodySection` is for.
+#[derive(Debug, Clone, PartialEq)]
+enum SectionText {
+    All,
+    Header,
+    HeaderFields(Vec<String>),
+    HeaderFieldsNot(Vec<String>),
+    Text,
+    Mime,
+}
+
+/// A body section to fetch, such as `BODY[1.2]` or `BODY.PEEK[HEADER.FIELDS (FROM)]<0.512>`:
+///
+/// ```ignore
+/// let preview = BodySection::new().text().partial(0, 4096).peek();
+/// let attachment = BodySection::part(&[2]).partial(1 << 20, 1 << 20);
+/// ```
+///
+/// Fetching a section sets `\Seen` on the message unless it is a `peek`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BodySection {
+    part: Vec<u32>,
+    text: SectionText,
+    peek: bool,
+    partial: Option<(u32, u32)>,
+}
+
+impl BodySection {
+    /// The whole message, `BODY[]`.
+    pub fn new() -> BodySection {
+        BodySection::part(&[])
+    }
+
+    /// The body part with the given path, such as `&[1, 2]` for `BODY[1.2]`.
+    pub fn part(part: &[u32]) -> BodySection {
+        BodySection {
+            part: part.to_vec(),
+            text: SectionText::All,
+            peek: false,
+            partial: None,
+        }
+    }
+
+    /// The header of the message, or of the message in a `message/rfc822` part.
+    pub fn header(mut self) -> BodySection {
+        self.text = SectionText::Header;
+        self
+    }
+
+    /// Only the header fields named.
+    pub fn header_fields<S: AsRef<str>>(mut self, fields: &[S]) -> BodySection {
+        self.text = SectionText::HeaderFields(BodySection::owned(fields));
+        self
+    }
+
+    /// The header without the fields named.
+    pub fn header_fields_not<S: AsRef<str>>(mut self, fields: &[S]) -> BodySection {
+        self.text = SectionText::HeaderFieldsNot(BodySection::owned(fields));
+        self
+    }
+
+    /// The text of the message without its header, or of the message in a `message/rfc822`
+    /// part.
+    pub fn text(mut self) -> BodySection {
+        self.text = SectionText::Text;
+        self
+    }
+
+    /// The MIME header of a body part.
+    pub fn mime(mut self) -> BodySection {
+        self.text = SectionText::Mime;
+        self
+    }
+
+    /// Fetches with `BODY.PEEK`, which does not set `\Seen`.
+    pub fn peek(mut self) -> BodySection {
+        self.peek = true;
+        self
+    }
+
+    /// Only `length` octets, starting at octet `start`. The server sends fewer if the section
+    /// ends first, and nothing if it ends before `start`. `length` can not be 0.
+    pub fn partial(mut self, start: u32, length: u32) -> BodySection {
+        self.partial = Some((start, length));
+        self
+    }
+
+    /// The section specifier, without the brackets.
+    fn spec(&self) -> String {
+        let mut spec = self.part.iter().map(|n| n.to_string()).collect::<Vec<_>>().join(".");
+        let text = match self.text {
+            SectionText::All => return spec,
+            SectionText::Header => "HEADER".to_owned(),
+            SectionText::HeaderFields(ref fields) => {
+                format!("HEADER.FIELDS ({})", BodySection::field_names(fields))
+            }
+            SectionText::HeaderFieldsNot(ref fields) => {
+                format!("HEADER.FIELDS.NOT ({})", BodySection::field_names(fields))
+            }
+            SectionText::Text => "TEXT".to_owned(),
+            SectionText::Mime => "MIME".to_owned(),
+        };
+
+        if !spec.is_empty() {
+            spec.push('.');
+        }
+        spec.push_str(&text);
+        spec
+    }
+
+    /// Fails if the section can not be asked for: part numbers start at 1, `MIME` needs a part,
+    /// `HEADER.FIELDS` needs at least one field name, which has to be printable ASCII, and a
+    /// partial range can not be empty.
+    fn check(&self) -> Result<(), IMAPError> {
+        if self.part.contains(&0) {
+            return Err(IMAPError::Invalid("Body part numbers start at 1".to_owned()));
+        }
+        if let Some((_, 0)) = self.partial {
+            return Err(IMAPError::Invalid("A partial fetch can not be empty".to_owned()));
+        }
+
+        match self.text {
+            SectionText::Mime if self.part.is_empty() => {
+                Err(IMAPError::Invalid("Only a body part has a MIME header".to_owned()))
+            }
+            SectionText::HeaderFields(ref fields) |
+            SectionText::HeaderFieldsNot(ref fields) => {
+                if fields.is_empty() {
+                    return Err(IMAPError::Invalid("No header fields were named".to_owned()));
+                }
+                for field in fields {
+                    let printable = field.bytes().all(|b| b > b' ' && b < 0x7f && b != b':');
+                    if field.is_empty() || !printable {
+                        return Err(IMAPError::Invalid(format!("{:?} is not a header field name",
+                                                              field)));
+                    }
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn owned<S: AsRef<str>>(fields: &[S]) -> Vec<String> {
+        fields.iter().map(|field| field.as_ref().to_owned()).collect()
+    }
+
+    fn field_names(fields: &[String]) -> String {
+        let names: Vec<String> = fields.iter().map(|f| BodySection::field_name(f)).collect();
+        names.join(" ")
+    }
+
+    /// A header field name as an atom where it can be one, or else as a quoted string.
+    fn field_name(field: &str) -> String {
+        let atom = !field.is_empty() &&
+                   field.bytes().all(|b| b > b' ' && b < 0x7f && !b"(){%*\"\\]".contains(&b));
+        if atom {
+            field.to_uppercase()
+        } else {
+            format!("\"{}\"", field.replace('\\', "\\\\").replace('"', "\\\""))
+        }
+    }
+
+    /// The name the server returns this section under, such as `BODY[1.2]<0>`.
+    fn response_name(&self) -> String {
+        match self.partial {
+            Some((start, _)) => format!("BODY[{}]<{}>", self.spec(), start),
+            None => format!("BODY[{}]", self.spec()),
+        }
+    }
+}
+
+impl Default for BodySection {
+    fn default() -> BodySection {
+        BodySection::new()
+    }
+}
+
+/// The section of a part found in a `BodyStructure`.
+impl<'a> From<&'a BodyStructure> for BodySection {
+    fn from(body: &BodyStructure) -> BodySection {
+        let part: Vec<u32> = body.section
+                                 .split('.')
+                                 .filter_map(|n| n.parse().ok())
+                                 .collect();
+        BodySection::part(&part)
+    }
+}
+
+impl fmt::Display for BodySection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{}[{}]", if self.peek { "BODY.PEEK" } else { "BODY" }, self.spec()));
+        if let Some((start, length)) = self.partial {
+            try!(write!(f, "<{}.{}>", start, length));
+        }
+        Ok(())
+    }
+}
+
+/// The data returned by `FETCH` for one message.
+///
+/// Each accessor returns `None` if its data item was not fetched. The `ALL`, `FAST` and `FULL`
+/// macros fetch the flags, internal date and size, and all but `FAST` fetch the envelope too.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fetch {
+    message: u32,
+    attributes: Vec<(String, Value)>,
+    uid: Option<u32>,
+    flags: Option<Vec<Flag>>,
+    envelope: Option<Envelope>,
+    internal_date: Option<DateTime>,
+    size: Option<u32>,
+    body_structure: Option<BodyStructure>,
+}
+
+impl Fetch {
+    fn new(message: u32, attributes: Vec<(String, Value)>) -> Fetch {
+        let mut fetch = Fetch {
+            message: message,
+            attributes: Vec::new(),
+            uid: None,
+            flags: None,
+            envelope: None,
+            internal_date: None,
+            size: None,
+            body_structure: None,
+        };
+
+        for &(ref name, ref value) in &attributes {
+            match &name[..] {
+                "UID" => fetch.uid = value.as_number().map(|n| n as u32),
+                "FLAGS" => fetch.flags = Mailbox::parse_flag_list(value),
+                "ENVELOPE" => fetch.envelope = Envelope::parse(value),
+                "INTERNALDATE" => {
+                    fetch.internal_date = value.as_string()
+                                               .and_then(|date| DateTime::parse(&date))
+                }
+                "RFC822.SIZE" => fetch.size = value.as_number().map(|n| n as u32),
+                "BODY" | "BODYSTRUCTURE" => fetch.body_structure = BodyStructure::parse(value),
+                _ => {}
+            }
+        }
+
+        fetch.attributes = attributes;
+        fetch
+    }
+
+    /// Parses one response, returning `None` if it is not a `FETCH` response.
+    fn parse(raw: &[u8]) -> Result<Option<Fetch>, IMAPError> {
+        match try!(response::parse(raw)) {
+            Response::Untagged(UntaggedResponse::Fetch(message, attributes)) => {
+                Ok(Some(Fetch::new(message, attributes)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// The sequence number of the message.
+    pub fn message(&self) -> u32 {
+        self.message
+    }
+
+    pub fn uid(&self) -> Option<u32> {
+        self.uid
+    }
+
+    pub fn flags(&self) -> Option<&[Flag]> {
+        self.flags.as_ref().map(|flags| &flags[..])
+    }
+
+    pub fn envelope(&self) -> Option<&Envelope> {
+        self.envelope.as_ref()
+    }
+
+    /// The date and time the server received the message.
+    pub fn internal_date(&self) -> Option<DateTime> {
+        self.internal_date
+    }
+
+    /// The size of the message in octets (`RFC822.SIZE`).
+    pub fn size(&self) -> Option<u32> {
+        self.size
+    }
+
+    /// The MIME structure of the message, if it was fetched with `BODYSTRUCTURE`, or with `BODY`
+    /// or the `FULL` macro for the structure without extension data.
+    pub fn body_structure(&self) -> Option<&BodyStructure> {
+        self.body_structure.as_ref()
+    }
+
+    /// The contents of a body section, by the name the server returned it under, such as
+    /// `BODY[HEADER]` or `BODY[1.2]`. Names are compared case-insensitively.
+    pub fn section(&self, name: &str) -> Option<&[u8]> {
+        let name = name.to_uppercase();
+        self.attributes
+            .iter()
+            .find(|&&(ref n, _)| *n == name)
+            .and_then(|&(_, ref value)| value.as_bytes())
+    }
+
+    /// The contents of a section that was fetched with a `BodySection`. For a partial fetch,
+    /// these are the octets the server sent, which may be fewer than were asked for.
+    pub fn body_section(&self, section: &BodySection) -> Option<&[u8]> {
+        // Servers may echo header field names quoted or not
+        let name = section.response_name().to_uppercase().replace('"', "");
+        self.attributes
+            .iter()
+            .find(|&&(ref n, _)| n.replace('"', "") == name)
+            .and_then(|&(_, ref value)| value.as_bytes())
+    }
+
+    /// Every body section returned, by the name the server returned it under, with its contents.
+    /// A section the server returned as `NIL` has no contents.
+    pub fn sections(&self) -> Vec<(&str, &[u8])> {
+        self.attributes
+            .iter()
+            .filter(|&&(ref name, _)| name.starts_with("BODY[") || name.starts_with("RFC822"))
+            .filter(|&&(ref name, _)| name != "RFC822.SIZE")
+            .map(|&(ref name, ref value)| (&name[..], value.as_bytes().unwrap_or(&[])))
+            .collect()
+    }
+
+    /// The full message, if it was fetched with `BODY[]`, `BODY.PEEK[]` or `RFC822`.
+    ///
+    /// The message is returned exactly as the server sent it, whatever its character set.
+    pub fn body(&self) -> Option<&[u8]> {
+        self.attributes
+            .iter()
+            .find(|&&(ref name, _)| name == "RFC822" || name.starts_with("BODY[]"))
+            .and_then(|&(_, ref value)| value.as_bytes())
+    }
+
+    /// The header of the message, if it was fetched with `BODY[HEADER]` or `RFC822.HEADER`.
+    pub fn header(&self) -> Option<&[u8]> {
+        self.section("BODY[HEADER]").or_else(|| self.section("RFC822.HEADER"))
+    }
+
+    /// The body of the message without its header, if it was fetched with `BODY[TEXT]` or
+    /// `RFC822.TEXT`.
+    pub fn text(&self) -> Option<&[u8]> {
+        self.section("BODY[TEXT]").or_else(|| self.section("RFC822.TEXT"))
+    }
+
+    /// All the data items returned, by their upper-cased names.
+    pub fn attributes(&self) -> &[(String, Value)] {
+        &self.attributes
+    }
+}
+
+impl Mailbox {
+    pub fn fetch<T: Into<SequenceSet>, D: Into<DataItem>>(&mut self, sequence_set: T, data_item: D) -> Result<Vec<Fetch>, IMAPError> {
+        let sequence_set = sequence_set.into();
+
+        if sequence_set.is_empty() {
+            return Err(IMAPError::Invalid("Can not FETCH an empty sequence set".to_owned()));
+        }
+        self.run_fetch("FETCH", &sequence_set.to_string(), data_item.into())
+    }
+
+    /// Like `fetch`, but for the messages with the UIDs in `uid_set`. The UID of each message is
+    /// always among the attributes returned.
+    ///
+    /// UIDs of messages that have been expunged are left out of the result, so it may be empty.
+    pub fn uid_fetch<T: Into<UidSet>, D: Into<DataItem>>(&mut self,
+                                                         uid_set: T,
+                                                         data_item: D)
+                                                         -> Result<Vec<Fetch>, IMAPError> {
+        let uid_set = uid_set.into();
+
+        if uid_set.is_empty() {
+            return Err(IMAPError::Invalid("Can not FETCH an empty UID set".to_owned()));
+        }
+        self.run_fetch("UID FETCH", &uid_set.to_string(), data_item.into())
+    }
+
+    /// Like `fetch`, but returns the messages one at a time as the server sends them, with the
+    /// contents of their body sections read straight from the connection. This keeps memory use
+    /// down when fetching large messages.
+    pub fn fetch_stream<T: Into<SequenceSet>, D: Into<DataItem>>(&mut self,
+                                                                 sequence_set: T,
+                                                                 data_item: D)
+                                                                 -> Result<FetchStream, IMAPError> {
+        let sequence_set = sequence_set.into();
+
+        if sequence_set.is_empty() {
+            return Err(IMAPError::Invalid("Can not FETCH an empty sequence set".to_owned()));
+        }
+        self.start_fetch_stream("FETCH", &sequence_set.to_string(), data_item.into())
+    }
+
+    /// Like `fetch_stream`, but for the messages with the UIDs in `uid_set`.
+    pub fn uid_fetch_stream<T: Into<UidSet>, D: Into<DataItem>>(&mut self,
+                                                                uid_set: T,
+                                                                data_item: D)
+                                                                -> Result<FetchStream, IMAPError> {
+        let uid_set = uid_set.into();
+
+        if uid_set.is_empty() {
+            return Err(IMAPError::Invalid("Can not FETCH an empty UID set".to_owned()));
+        }
+        self.start_fetch_stream("UID FETCH", &uid_set.to_string(), data_item.into())
+    }
+
+    fn start_fetch_stream(&mut self,
+                          command: &str,
+                          set: &str,
+                          data_item: DataItem)
+                          -> Result<FetchStream, IMAPError> {
+        let tag = self.server.tag.next_tag();
+        let cmd = try!(Mailbox::fetch_command(command, set, data_item));
+
+        // The command has no literals, so the server can not complete it before it is sent
+        try!(self.server.imap.send(&tag, &cmd, &mut Vec::new()));
+        Ok(FetchStream {
+            mailbox: self,
+            tag: tag,
+            done: false,
+            message: None,
+            text: Vec::new(),
+            section: None,
+            remaining: 0,
+            reading: false,
+        })
+    }
+
+    fn run_fetch(&mut self,
+                 command: &str,
+                 set: &str,
+                 data_item: DataItem)
+                 -> Result<Vec<Fetch>, IMAPError> {
+        let tag = self.server.tag.next_tag();
+        let cmd = try!(Mailbox::fetch_command(command, set, data_item));
+
+        let response = try!(self.server.command(&tag, &cmd));
+        try!(IMAPClient::check_tagged_response(&response, &tag));
+        let response = try!(Mailbox::parse_fetch_response(&response));
+        Ok(response)
+    }
+
+    fn fetch_command(command: &str, set: &str, data_item: DataItem) -> Result<Command, IMAPError> {
+        let data_item = match data_item {
+            DataItem::Macro(m)  => {
+                match m {
+                    Macro::All  => "ALL".to_owned(),
+                    Macro::Fast => "FAST".to_owned(),
+                    Macro::Full => "FULL".to_owned(),
+                }
+            },
+            DataItem::Raw(r)    => r,
+            DataItem::Attributes(attributes) => {
+                if attributes.is_empty() {
+                    return Err(IMAPError::Invalid("Can not FETCH no attributes".to_owned()));
+                }
+                for attribute in &attributes {
+                    if let Attribute::Section(ref section) = *attribute {
+                        try!(section.check());
+                    }
+                }
+                let names: Vec<String> = attributes.iter().map(|a| a.to_string()).collect();
+                format!("({})", names.join(" "))
+            }
+        };
+
+        Ok(Command::new(command).raw(set).raw(&data_item))
+    }
+
+    fn parse_fetch_response(res: &[Vec<u8>]) -> Result<Vec<Fetch>, IMAPError> {
+        let mut fetches = Vec::new();
+
+        for raw in res {
+            if let Some(fetch) = try!(Fetch::parse(raw)) {
+                fetches.push(fetch);
+            }
+        }
+
+        Ok(fetches)
+    }
+}
+
+/// The response to a `FETCH` being read one message at a time, returned by
+/// `Mailbox::fetch_stream`:
+///
+/// ```ignore
+/// let mut stream = try!(mailbox.fetch_stream((1, 10), BodySection::new().peek()));
+/// while let Some(mut message) = try!(stream.next()) {
+///     let mut file = try!(File::create(format!("{}.eml", message.message())));
+///     while let Some(mut section) = try!(message.next_section()) {
+///         try!(io::copy(&mut section, &mut file));
+///     }
+/// }
+/// ```
+///
+/// No other commands can be sent until the stream is dropped. Dropping it early reads and
+/// discards the rest of the response.
+#[derive(Debug)]
+pub struct FetchStream<'a> {
+    mailbox: &'a mut Mailbox,
+    tag: String,
+    done: bool,
+    /// The sequence number of the message being read.
+    message: Option<u32>,
+    /// The response for that message read so far, with the body sections left out.
+    text: Vec<u8>,
+    /// The name of the body section the connection is in the middle of.
+    section: Option<String>,
+    /// What is left of that section.
+    remaining: usize,
+    /// Whether that section has been handed out by `next_section`.
+    reading: bool,
+}
+
+impl<'a> FetchStream<'a> {
+    /// Reads up to the next message, skipping whatever is left of the last one. Returns `None`
+    /// once the server has completed the `FETCH`.
+    pub fn next<'s>(&'s mut self) -> Result<Option<FetchMessage<'s, 'a>>, IMAPError> {
+        while self.message.is_some() {
+            try!(self.next_section());
+        }
+
+        while !self.done {
+            let line = try!(self.read_line());
+
+            if line.starts_with(format!("{} ", self.tag).as_bytes()) {
+                self.done = true;
+                try!(IMAPClient::check_tagged_response(&[line], &self.tag));
+                return Ok(None);
+            }
+
+            match FetchStream::fetch_number(&line) {
+                Some(message) => {
+                    self.message = Some(message);
+                    self.text.clear();
+                    try!(self.read_through(line));
+                    return Ok(Some(FetchMessage {
+                        stream: self,
+                        message: message,
+                    }));
+                }
+                None => {
+                    // Untagged responses other than FETCH are not kept
+                    let rest = self.mailbox.server.imap.read_rest_of_response(line);
+                    try!(self.check(rest));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Moves on to the next body section of the current message, skipping what is left of the
+    /// last one. Returns `None` once the message is complete.
+    fn next_section(&mut self) -> Result<Option<String>, IMAPError> {
+        if self.reading {
+            let mut skip = [0; 4096];
+            while self.remaining > 0 {
+                let len = cmp::min(skip.len(), self.remaining);
+                let read = try!(self.mailbox.server.imap.read_literal(&mut skip[..len]));
+                if read == 0 {
+                    self.done = true;
+                    return Err(IMAPError::ConnectError("Connection closed by server".to_owned()));
+                }
+                self.remaining -= read;
+            }
+
+            self.reading = false;
+            self.section = None;
+            let line = try!(self.read_line());
+            try!(self.read_through(line));
+        }
+
+        match self.section {
+            Some(ref name) => {
+                self.reading = true;
+                Ok(Some(name.clone()))
+            }
+            None => {
+                self.message = None;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Adds `line` and what follows it to the response text, up to the next body section or
+    /// the end of the response. Other literals, such as those in an envelope, are read in full.
+    fn read_through(&mut self, mut line: Vec<u8>) -> Result<(), IMAPError> {
+        loop {
+            let len = match stream::literal_length(&line) {
+                Some(len) => len,
+                None => {
+                    self.text.extend_from_slice(&line);
+                    return Ok(());
+                }
+            };
+
+            let marker = line.iter().rposition(|&b| b == b'{').unwrap_or(line.len());
+            let name = FetchStream::attribute_name(&line[..marker]);
+            if FetchStream::is_section(&name) {
+                self.text.extend_from_slice(&line[..marker]);
+                self.text.extend_from_slice(b"{0}\r\n");
+                self.section = Some(name);
+                self.remaining = len;
+                return Ok(());
+            }
+
+            self.text.extend_from_slice(&line);
+            let read = self.mailbox.server.imap.read_literal_to_end(len, &mut self.text);
+            try!(self.check(read));
+
+            line = try!(self.read_line());
+        }
+    }
+
+    fn read_line(&mut self) -> Result<Vec<u8>, IMAPError> {
+        let mut line = Vec::new();
+        let read = self.mailbox.server.imap.read_line(&mut line);
+        try!(self.check(read));
+        Ok(line)
+    }
+
+    /// Gives up on the response after an error reading from the connection, as what was read
+    /// can no longer be trusted to line up with it.
+    fn check<T>(&mut self, result: Result<T, IMAPError>) -> Result<T, IMAPError> {
+        if result.is_err() {
+            self.done = true;
+            self.message = None;
+        }
+        result
+    }
+
+    /// The sequence number of a line starting a `FETCH` response.
+    fn fetch_number(line: &[u8]) -> Option<u32> {
+        let line = String::from_utf8_lossy(line);
+        let mut words = line.split(' ');
+        if words.next() != Some("*") {
+            return None;
+        }
+        let message = try_opt!(try_opt!(words.next()).parse().ok());
+        if try_opt!(words.next()).to_uppercase() != "FETCH" {
+            return None;
+        }
+        Some(message)
+    }
+
+    /// The last word of `text`, keeping a bracketed section such as
+    /// `BODY[HEADER.FIELDS (FROM)]<0>` whole.
+    fn attribute_name(text: &[u8]) -> String {
+        let text = match text.last() {
+            Some(&b' ') => &text[..text.len() - 1],
+            _ => text,
+        };
+
+        let mut depth = 0;
+        let mut start = text.len();
+        while start > 0 {
+            match text[start - 1] {
+                b']' => depth += 1,
+                b'[' if depth > 0 => depth -= 1,
+                b' ' | b'(' if depth == 0 => break,
+                _ => {}
+            }
+            start -= 1;
+        }
+        String::from_utf8_lossy(&text[start..]).to_uppercase()
+    }
+
+    fn is_section(name: &str) -> bool {
+        name.starts_with("BODY[") || name.starts_with("BINARY[") || name == "RFC822" ||
+        name == "RFC822.HEADER" || name == "RFC822.TEXT"
+    }
+}
+
+impl<'a> Drop for FetchStream<'a> {
+    fn drop(&mut self) {
+        // Errors can not be reported from here; they will show up on the next command instead
+        while !self.done {
+            if self.next().is_err() {
+                break;
+            }
+        }
+    }
+}
+
+/// One message of a `FetchStream`.
+#[derive(Debug)]
+pub struct FetchMessage<'s, 'a: 's> {
+    stream: &'s mut FetchStream<'a>,
+    message: u32,
+}
+
+impl<'s, 'a> FetchMessage<'s, 'a> {
+    /// The sequence number of the message.
+    pub fn message(&self) -> u32 {
+        self.message
+    }
+
+    /// Returns a reader for the next body section of the message, skipping what is left of the
+    /// last one. Returns `None` once there are no more.
+    pub fn next_section(&mut self) -> Result<Option<SectionReader>, IMAPError> {
+        let name = match try!(self.stream.next_section()) {
+            Some(name) => name,
+            None => return Ok(None),
+        };
+
+        Ok(Some(SectionReader {
+            name: name,
+            imap: &mut self.stream.mailbox.server.imap,
+            remaining: &mut self.stream.remaining,
+        }))
+    }
+
+    /// Skips the rest of the message and returns its other data items, such as its UID and
+    /// flags. The contents of the body sections are left empty.
+    pub fn finish(self) -> Result<Fetch, IMAPError> {
+        while try!(self.stream.next_section()).is_some() {}
+
+        let text = mem::replace(&mut self.stream.text, Vec::new());
+        match try!(Fetch::parse(&text)) {
+            Some(fetch) => Ok(fetch),
+            None => Err(IMAPError::Invalid(String::from_utf8_lossy(&text).into_owned())),
+        }
+    }
+}
+
+/// The contents of a body section, read from the connection as they arrive.
+#[derive(Debug)]
+pub struct SectionReader<'r> {
+    name: String,
+    imap: &'r mut Stream,
+    remaining: &'r mut usize,
+}
+
+impl<'r> SectionReader<'r> {
+    /// The name the server returned the section under, such as `BODY[2]<0>`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// How many octets are left to read.
+    pub fn remaining(&self) -> usize {
+        *self.remaining
+    }
+}
+
+impl<'r> Read for SectionReader<'r> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if *self.remaining == 0 || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let len = cmp::min(buf.len(), *self.remaining);
+        let read = try!(self.imap.read_literal(&mut buf[..len]));
+        if read == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed by server"));
+        }
+        *self.remaining -= read;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use Mailbox;
+    use super::{BodySection, BodyStructure, Fetch};
+
+    const TEXT: &'static str = "(\"TEXT\" \"PLAIN\" (\"CHARSET\" \"US-ASCII\") NIL NIL \"7BIT\" 10 \
+                                1)";
+    const OCTETS: &'static str = "(\"APPLICATION\" \"OCTET-STREAM\" NIL NIL NIL \"BASE64\" 20)";
+    const GIF: &'static str = "(\"IMAGE\" \"GIF\" NIL NIL NIL \"BASE64\" 30)";
+    const RICHTEXT: &'static str = "(\"TEXT\" \"RICHTEXT\" NIL NIL NIL \"7BIT\" 10 1)";
+
+    fn message(body: &str) -> String {
+        format!("(\"MESSAGE\" \"RFC822\" NIL NIL NIL \"7BIT\" 100 \
+                 (NIL NIL NIL NIL NIL NIL NIL NIL NIL NIL) {} 5)",
+                body)
+    }
+
+    fn body_structure(body: &str) -> BodyStructure {
+        let raw = format!("* 1 FETCH (BODYSTRUCTURE {})\r\n", body);
+        let fetch = Fetch::parse(raw.as_bytes()).unwrap().unwrap();
+        fetch.body_structure().unwrap().clone()
+    }
+
+    #[test]
+    fn fetch_of_expunged_messages_is_empty() {
+        let response = vec![b"a7 OK UID FETCH completed\r\n".to_vec()];
+        assert!(Mailbox::parse_fetch_response(&response).unwrap().is_empty());
+    }
+
+    #[test]
+    fn invalid_sections_are_refused() {
+        let sections = [BodySection::new().mime(),
+                        BodySection::new().header_fields::<&str>(&[]),
+                        BodySection::part(&[1]).header_fields_not::<&str>(&[]),
+                        BodySection::new().header_fields(&["Subject", "X-Bad\r\nA1 LOGOUT"]),
+                        BodySection::new().header_fields(&["Subject:"]),
+                        BodySection::new().header_fields(&[""]),
+                        BodySection::part(&[1, 0]),
+                        BodySection::new().partial(10, 0)];
+        for section in &sections {
+            assert!(Mailbox::fetch_command("FETCH", "1", section.clone().into()).is_err(),
+                    "{}",
+                    section);
+        }
+
+        let section = BodySection::part(&[2]).mime();
+        assert!(Mailbox::fetch_command("FETCH", "1", section.into()).is_ok());
+        let section = BodySection::new().header_fields(&["From", "X-Spam(Score)"]);
+        assert!(Mailbox::fetch_command("FETCH", "1", section.into()).is_ok());
+        let section = BodySection::new().partial(0, 1);
+        assert!(Mailbox::fetch_command("FETCH", "1", section.into()).is_ok());
+    }
+
+    #[test]
+    fn body_structure_sections() {
+        // The example of section 6.4.5 of RFC 3501
+        let alternative = format!("({}{} \"ALTERNATIVE\")", TEXT, RICHTEXT);
+        let part_4_2 = message(&format!("({}{} \"MIXED\")", TEXT, alternative));
+        let body = format!("({}{}{}({}{} \"MIXED\") \"MIXED\")",
+                           TEXT,
+                           OCTETS,
+                           message(&format!("({}{} \"MIXED\")", TEXT, OCTETS)),
+                           GIF,
+                           part_4_2);
+        let body = body_structure(&body);
+
+        let sections = [("", "multipart/mixed"),
+                        ("1", "text/plain"),
+                        ("2", "application/octet-stream"),
+                        ("3", "message/rfc822"),
+                        ("3.1", "text/plain"),
+                        ("3.2", "application/octet-stream"),
+                        ("4", "multipart/mixed"),
+                        ("4.1", "image/gif"),
+                        ("4.2", "message/rfc822"),
+                        ("4.2.1", "text/plain"),
+                        ("4.2.2", "multipart/alternative"),
+                        ("4.2.2.1", "text/plain"),
+                        ("4.2.2.2", "text/richtext")];
+        for &(section, media_type) in &sections {
+            let part = body.find(section).unwrap();
+            assert_eq!(part.section(), section);
+            assert_eq!(format!("{}/{}", part.media_type, part.subtype), media_type);
+        }
+
+        // The body of a message/rfc822 part is a multipart with the part's own section
+        let part_3 = body.find("3").unwrap();
+        assert_eq!(part_3.message.as_ref().unwrap().section(), "3");
+        assert_eq!(part_3.lines, Some(5));
+        assert!(part_3.envelope.is_some());
+        assert_eq!(body.find("5"), None);
+
+        let part = body.find("4.2.2.1").unwrap();
+        assert_eq!(BodySection::from(part).to_string(), "BODY[4.2.2.1]");
+        assert_eq!(part.params, [("charset".to_owned(), "US-ASCII".to_owned())]);
+    }
+
+    #[test]
+    fn body_structure_of_single_part() {
+        let body = body_structure(TEXT);
+        assert!(!body.is_multipart());
+        assert_eq!(body.section(), "1");
+        assert_eq!(body.lines, Some(1));
+
+        // A message/rfc822 part that is not multipart has its body in the section below it
+        let body = body_structure(&format!("({}{} \"MIXED\")", TEXT, message(GIF)));
+        assert_eq!(body.find("2").unwrap().media_type, "message");
+        assert_eq!(body.find("2.1").unwrap().media_type, "image");
+    }
+}
@@ -0,0 +1,114 @@
+//! Message flags, as set with `STORE` and returned by `FETCH` and `SELECT`.
+
+use command::{self, Command};
+use imaperror::IMAPError;
+use std::fmt;
+
+/// A message flag. System flags are case-insensitive and start with a backslash; keywords are
+/// defined by clients and kept as sent.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Flag {
+    Seen,
+    Answered,
+    Flagged,
+    Deleted,
+    Draft,
+    /// Set by the server only; it can not be stored.
+    Recent,
+    /// `\*` in the `PERMANENTFLAGS` of a mailbox, meaning new keywords can be created in it.
+    MayCreate,
+    /// A keyword, or a system flag this library does not know. Keywords have to be atoms, such
+    /// as `$Junk`, to be stored.
+    Custom(String),
+}
+
+impl<'a> From<&'a str> for Flag {
+    fn from(flag: &str) -> Flag {
+        match &flag.to_lowercase()[..] {
+            "\\seen" => Flag::Seen,
+            "\\answered" => Flag::Answered,
+            "\\flagged" => Flag::Flagged,
+            "\\deleted" => Flag::Deleted,
+            "\\draft" => Flag::Draft,
+            "\\recent" => Flag::Recent,
+            "\\*" => Flag::MayCreate,
+            _ => Flag::Custom(flag.to_owned()),
+        }
+    }
+}
+
+impl fmt::Display for Flag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Flag::Seen => write!(f, "\\Seen"),
+            Flag::Answered => write!(f, "\\Answered"),
+            Flag::Flagged => write!(f, "\\Flagged"),
+            Flag::Deleted => write!(f, "\\Deleted"),
+            Flag::Draft => write!(f, "\\Draft"),
+            Flag::Recent => write!(f, "\\Recent"),
+            Flag::MayCreate => write!(f, "\\*"),
+            Flag::Custom(ref name) => write!(f, "{}", name),
+        }
+    }
+}
+
+impl Command {
+    /// Appends `flags` as a parenthesised list. Fails if a flag can not be stored: `Recent` and
+    /// `MayCreate`, or a `Custom` flag that is not an atom, or a backslash followed by one for a
+    /// system flag this library does not know.
+    pub fn flags(self, flags: &[Flag]) -> Result<Command, IMAPError> {
+        let mut cmd = self.open_list();
+        for flag in flags {
+            match *flag {
+                Flag::Recent | Flag::MayCreate => {
+                    return Err(IMAPError::Invalid(format!("{} can not be stored", flag)));
+                }
+                Flag::Custom(ref name) => {
+                    let atom = if name.starts_with('\\') { &name[1..] } else { &name[..] };
+                    if atom.is_empty() || !atom.bytes().all(command::is_atom_char) {
+                        return Err(IMAPError::Invalid(format!("{:?} is not a valid flag", name)));
+                    }
+                }
+                _ => {}
+            }
+            cmd = cmd.raw(&flag.to_string());
+        }
+        Ok(cmd.close_list())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use command::{Command, Part};
+    use super::Flag;
+
+    #[test]
+    fn flag_list() {
+        let flags = [Flag::Seen,
+                     Flag::from("$Junk"),
+                     Flag::from("\\Important"),
+                     Flag::from("\\DELETED")];
+        let cmd = Command::new("STORE 1 +FLAGS").flags(&flags).unwrap();
+        assert_eq!(cmd.parts(),
+                   [Part::Text(b"STORE 1 +FLAGS (\\Seen $Junk \\Important \\Deleted)".to_vec())]);
+    }
+
+    #[test]
+    fn invalid_flags() {
+        for name in &["", "\\", "two words", "a]b", "\"quoted\"", "\\Not Atom", "caf\u{e9}",
+                      "(x)", "\\\\x"] {
+            assert!(Command::new("STORE").flags(&[Flag::Custom(name.to_string())]).is_err(),
+                    "{:?}",
+                    name);
+        }
+    }
+
+    #[test]
+    fn server_flags_are_refused() {
+        for flag in &[Flag::Recent, Flag::MayCreate, Flag::from("\\RECENT"), Flag::from("\\*")] {
+            assert!(Command::new("STORE").flags(&[Flag::Seen, flag.clone()]).is_err(),
+                    "{:?}",
+                    flag);
+        }
+    }
+}
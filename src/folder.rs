@@ -0,0 +1,163 @@
+//! Mailboxes as returned by `LIST` and `LSUB`, and the folder hierarchy they make up.
+
+use response::MailboxList;
+use utf7;
+
+/// A mailbox name attribute, from RFC 3501 section 7.2.2 and its extensions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NameAttribute {
+    NoInferiors,
+    NoSelect,
+    Marked,
+    Unmarked,
+    HasChildren,
+    HasNoChildren,
+    NonExistent,
+    Subscribed,
+    /// The special-use attributes of RFC 6154.
+    All,
+    Archive,
+    Drafts,
+    Flagged,
+    Junk,
+    Sent,
+    Trash,
+    /// Any other attribute, as sent by the server.
+    Other(String),
+}
+
+impl<'a> From<&'a str> for NameAttribute {
+    fn from(attribute: &str) -> NameAttribute {
+        match &attribute.to_lowercase()[..] {
+            "\\noinferiors" => NameAttribute::NoInferiors,
+            "\\noselect" => NameAttribute::NoSelect,
+            "\\marked" => NameAttribute::Marked,
+            "\\unmarked" => NameAttribute::Unmarked,
+            "\\haschildren" => NameAttribute::HasChildren,
+            "\\hasnochildren" => NameAttribute::HasNoChildren,
+            "\\nonexistent" => NameAttribute::NonExistent,
+            "\\subscribed" => NameAttribute::Subscribed,
+            "\\all" => NameAttribute::All,
+            "\\archive" => NameAttribute::Archive,
+            "\\drafts" => NameAttribute::Drafts,
+            "\\flagged" => NameAttribute::Flagged,
+            "\\junk" => NameAttribute::Junk,
+            "\\sent" => NameAttribute::Sent,
+            "\\trash" => NameAttribute::Trash,
+            _ => NameAttribute::Other(attribute.to_owned()),
+        }
+    }
+}
+
+/// A mailbox returned by `LIST` or `LSUB`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Folder {
+    attributes: Vec<NameAttribute>,
+    delimiter: Option<String>,
+    name: String,
+}
+
+impl Folder {
+    pub fn attributes(&self) -> &[NameAttribute] {
+        &self.attributes
+    }
+
+    pub fn has_attribute(&self, attribute: &NameAttribute) -> bool {
+        self.attributes.contains(attribute)
+    }
+
+    /// Whether the mailbox can be selected, i.e. it is not `\Noselect` or `\NonExistent`.
+    pub fn is_selectable(&self) -> bool {
+        !self.has_attribute(&NameAttribute::NoSelect) &&
+        !self.has_attribute(&NameAttribute::NonExistent)
+    }
+
+    /// The hierarchy delimiter, or `None` if the server has no hierarchy.
+    pub fn delimiter(&self) -> Option<&str> {
+        self.delimiter.as_ref().map(|delimiter| &delimiter[..])
+    }
+
+    /// The full name of the mailbox, decoded from modified UTF-7.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The components of the name, split on the hierarchy delimiter.
+    pub fn path(&self) -> Vec<&str> {
+        match self.delimiter {
+            Some(ref delimiter) if !delimiter.is_empty() => self.name.split(&delimiter[..]).collect(),
+            _ => vec![&self.name[..]],
+        }
+    }
+}
+
+impl From<MailboxList> for Folder {
+    fn from(list: MailboxList) -> Folder {
+        // Names that are not valid modified UTF-7 are kept as they were sent
+        let name = utf7::decode(&list.name).unwrap_or(list.name);
+
+        Folder {
+            attributes: list.attributes.iter().map(|a| NameAttribute::from(&a[..])).collect(),
+            delimiter: list.delimiter,
+            name: name,
+        }
+    }
+}
+
+/// A node in the folder hierarchy built by `tree`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FolderNode {
+    name: String,
+    folder: Option<Folder>,
+    children: Vec<FolderNode>,
+}
+
+impl FolderNode {
+    /// The last component of the folder's name.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The listed mailbox at this position, or `None` for a parent that was not itself listed,
+    /// as happens when `LIST` is given a pattern like `%/Archive`.
+    pub fn folder(&self) -> Option<&Folder> {
+        self.folder.as_ref()
+    }
+
+    pub fn children(&self) -> &[FolderNode] {
+        &self.children
+    }
+}
+
+/// Arranges `folders` into a hierarchy, using each folder's delimiter. Siblings keep the order
+/// in which they were listed.
+pub fn tree(folders: Vec<Folder>) -> Vec<FolderNode> {
+    let mut roots: Vec<FolderNode> = Vec::new();
+
+    for folder in folders {
+        let path: Vec<String> = folder.path().iter().map(|c| (*c).to_owned()).collect();
+        insert(&mut roots, &path, folder);
+    }
+
+    roots
+}
+
+fn insert(nodes: &mut Vec<FolderNode>, path: &[String], folder: Folder) {
+    let position = match nodes.iter().position(|node| node.name == path[0]) {
+        Some(position) => position,
+        None => {
+            nodes.push(FolderNode {
+                name: path[0].clone(),
+                folder: None,
+                children: Vec::new(),
+            });
+            nodes.len() - 1
+        }
+    };
+
+    if path.len() == 1 {
+        nodes[position].folder = Some(folder);
+    } else {
+        insert(&mut nodes[position].children, &path[1..], folder);
+    }
+}
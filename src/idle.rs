@@ -0,0 +1,330 @@
+//! Waiting for changes to the selected mailbox with `IDLE` (RFC 2177).
+
+use capability::Capability;
+use command::Command;
+use imaperror::IMAPError;
+use response::{self, Response, Status, UntaggedResponse};
+use std::cmp;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use {IMAPClient, Mailbox};
+
+/// How often a waiting handle checks whether it has been interrupted.
+const POLL_INTERVAL_MS: u64 = 500;
+
+/// Servers may log out a client that has been idle for 30 minutes, and RFC 2177 asks clients to
+/// restart `IDLE` at least every 29.
+const DEFAULT_KEEPALIVE_SECS: u64 = 28 * 60;
+
+/// What `IdleHandle::wait` returned for.
+#[derive(Debug, Clone, PartialEq)]
+pub enum IdleEvent {
+    /// The server reported changes: `Exists`, `Recent`, `Expunge` or `Fetch` responses.
+    Updates(Vec<UntaggedResponse>),
+    /// Nothing happened before the timeout passed. The handle is still idling.
+    TimedOut,
+    /// `IdleInterrupt::interrupt` was called, and `IDLE` has been ended.
+    Interrupted,
+}
+
+/// Ends an `IdleHandle`'s `IDLE` from another thread.
+#[derive(Debug, Clone)]
+pub struct IdleInterrupt {
+    interrupted: Arc<AtomicBool>,
+}
+
+impl IdleInterrupt {
+    /// Makes the handle send `DONE` and return `IdleEvent::Interrupted` from `wait`. This takes
+    /// effect within half a second if the handle is waiting, or else when `wait` is next called.
+    pub fn interrupt(&self) {
+        self.interrupted.store(true, Ordering::SeqCst);
+    }
+}
+
+/// A mailbox in the `IDLE` state, returned by `Mailbox::idle`.
+///
+/// No other commands can be sent while idling. `IDLE` is ended with `done`, by an
+/// `IdleInterrupt`, or when the handle is dropped.
+#[derive(Debug)]
+pub struct IdleHandle<'a> {
+    mailbox: &'a mut Mailbox,
+    tag: String,
+    started: Instant,
+    timeout: Option<Duration>,
+    keepalive: Duration,
+    interrupted: Arc<AtomicBool>,
+    partial: Vec<u8>,
+    pending: Vec<UntaggedResponse>,
+    idling: bool,
+}
+
+impl Mailbox {
+    /// Starts `IDLE`, after which the server reports changes to the mailbox as they happen.
+    /// Needs a server that supports `IDLE`.
+    pub fn idle(&mut self) -> Result<IdleHandle, IMAPError> {
+        if !try!(self.capabilities()).has(&Capability::Idle) {
+            return Err(IMAPError::Unsupported("IDLE".to_owned()));
+        }
+
+        let mut handle = IdleHandle {
+            mailbox: self,
+            tag: String::new(),
+            started: Instant::now(),
+            timeout: None,
+            keepalive: Duration::from_secs(DEFAULT_KEEPALIVE_SECS),
+            interrupted: Arc::new(AtomicBool::new(false)),
+            partial: Vec::new(),
+            pending: Vec::new(),
+            idling: false,
+        };
+        try!(handle.start());
+        Ok(handle)
+    }
+}
+
+impl<'a> IdleHandle<'a> {
+    /// Sets how long `wait` blocks before returning `IdleEvent::TimedOut`. With `None`, the
+    /// default, it waits until something happens.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Sets how often `IDLE` is restarted to keep the connection alive. The default is 28
+    /// minutes.
+    pub fn set_keepalive(&mut self, keepalive: Duration) {
+        self.keepalive = keepalive;
+    }
+
+    /// Returns a handle that can end this `IDLE` from another thread.
+    pub fn interrupter(&self) -> IdleInterrupt {
+        IdleInterrupt { interrupted: self.interrupted.clone() }
+    }
+
+    /// Blocks until the server reports changes to the mailbox, the timeout passes, or `IDLE` is
+    /// interrupted. `IDLE` is restarted as needed while waiting.
+    pub fn wait(&mut self) -> Result<IdleEvent, IMAPError> {
+        let called = Instant::now();
+        let poll = Duration::from_millis(POLL_INTERVAL_MS);
+
+        loop {
+            if self.interrupted.load(Ordering::SeqCst) {
+                try!(self.stop());
+                return Ok(IdleEvent::Interrupted);
+            }
+
+            if !self.pending.is_empty() && !self.mailbox.server.imap.has_buffered_line() {
+                let updates = self.pending.split_off(0);
+                return Ok(IdleEvent::Updates(updates));
+            }
+
+            let idled = self.started.elapsed();
+            if idled >= self.keepalive {
+                try!(self.stop());
+                try!(self.start());
+                continue;
+            }
+
+            let mut wait = cmp::min(poll, self.keepalive - idled);
+            if let Some(timeout) = self.timeout {
+                let elapsed = called.elapsed();
+                if elapsed >= timeout {
+                    return Ok(IdleEvent::TimedOut);
+                }
+                wait = cmp::min(wait, timeout - elapsed);
+            }
+            if wait == Duration::from_secs(0) {
+                continue;
+            }
+
+            try!(self.mailbox.server.imap.set_read_timeout(Some(wait)));
+            if let Some(raw) = try!(self.mailbox.server.imap.poll_response(&mut self.partial)) {
+                try!(self.handle(&raw));
+            }
+        }
+    }
+
+    /// Ends `IDLE`, returning any changes the server reported that `wait` has not returned yet.
+    pub fn done(mut self) -> Result<Vec<UntaggedResponse>, IMAPError> {
+        try!(self.stop());
+        Ok(self.pending.split_off(0))
+    }
+
+    /// Sends `IDLE` and waits for the server to accept it.
+    fn start(&mut self) -> Result<(), IMAPError> {
+        self.tag = self.mailbox.server.tag.next_tag();
+        self.started = Instant::now();
+
+        let mut responses = Vec::new();
+        let cmd = Command::new("IDLE");
+        if !try!(self.mailbox.server.imap.begin(&self.tag, &cmd, &mut responses)) {
+            try!(IMAPClient::check_tagged_response(&responses, &self.tag));
+            return Err(IMAPError::Invalid("Server completed IDLE without accepting it".to_owned()));
+        }
+
+        self.idling = true;
+        for raw in &responses {
+            try!(self.handle(raw));
+        }
+        Ok(())
+    }
+
+    /// Sends `DONE` and reads the rest of the `IDLE` response.
+    fn stop(&mut self) -> Result<(), IMAPError> {
+        if !self.idling {
+            return Ok(());
+        }
+        self.idling = false;
+
+        try!(self.mailbox.server.imap.restore_read_timeout());
+        if !self.partial.is_empty() {
+            // With the usual timeout, this waits for the rest of the response a poll cut short
+            if let Some(raw) = try!(self.mailbox.server.imap.poll_response(&mut self.partial)) {
+                try!(self.handle(&raw));
+            }
+        }
+        let responses = try!(self.mailbox.server.imap.finish(&self.tag, b"DONE\r\n"));
+
+        let untagged = try!(IMAPClient::check_tagged_response(&responses, &self.tag));
+        for data in untagged {
+            self.update(data);
+        }
+        Ok(())
+    }
+
+    /// Keeps the updates among the untagged responses read while idling.
+    fn handle(&mut self, raw: &[u8]) -> Result<(), IMAPError> {
+        match try!(response::parse(raw)) {
+            Response::Untagged(UntaggedResponse::Status(ref status)) if status.status ==
+                                                                         Status::Bye => {
+                self.idling = false;
+                Err(IMAPError::ConnectError(status.text.clone()))
+            }
+            Response::Untagged(data) => {
+                self.update(data);
+                Ok(())
+            }
+            Response::Tagged(tag, completion) => {
+                if tag != self.tag {
+                    return Ok(());
+                }
+
+                // The server ended IDLE by itself, so there is nothing left for DONE to end
+                self.idling = false;
+                try!(IMAPClient::check_status(completion));
+                self.start()
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn update(&mut self, data: UntaggedResponse) {
+        match data {
+            UntaggedResponse::Exists(_) |
+            UntaggedResponse::Recent(_) |
+            UntaggedResponse::Expunge(_) |
+            UntaggedResponse::Fetch(..) => self.pending.push(data),
+            _ => {}
+        }
+    }
+}
+
+impl<'a> Drop for IdleHandle<'a> {
+    fn drop(&mut self) {
+        // Errors can not be reported from here; they will show up on the next command instead
+        let _ = self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use imaperror::IMAPError;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use super::IdleEvent;
+    use {IMAPClient, IMAPConnection};
+
+    /// Runs `server` against a client that has selected a mailbox, then runs `client` on it.
+    fn with_mailbox<S, C>(server: S, client: C)
+        where S: FnOnce(&mut BufReader<TcpStream>, &mut TcpStream) + Send + 'static,
+              C: FnOnce(&mut ::Mailbox)
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            writer.write_all(b"* PREAUTH [CAPABILITY IMAP4rev1 IDLE] ready\r\n").unwrap();
+            let tag = command(&mut reader, "SELECT");
+            writer.write_all(format!("* 2 EXISTS\r\n* 0 RECENT\r\n* FLAGS ()\r\n{} OK done\r\n", tag)
+                                 .as_bytes())
+                  .unwrap();
+            server(&mut reader, &mut writer);
+        });
+
+        let imap = IMAPClient::connect(IMAPConnection::Basic(TcpStream::connect(addr).unwrap()));
+        match imap.unwrap().select("INBOX") {
+            Ok(IMAPClient::Selected(ref mut mailbox)) => client(mailbox),
+            _ => panic!("SELECT failed"),
+        }
+        server.join().unwrap();
+    }
+
+    /// Reads a command, checks that it is `name`, and returns its tag.
+    fn command(reader: &mut BufReader<TcpStream>, name: &str) -> String {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        let tag = line.split(' ').next().unwrap().to_owned();
+        assert!(line[tag.len() + 1..].starts_with(name), "{:?}", line);
+        tag
+    }
+
+    fn line(reader: &mut BufReader<TcpStream>) -> String {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        line
+    }
+
+    #[test]
+    fn restarts_when_server_ends_idle() {
+        with_mailbox(|reader, writer| {
+            let tag = command(reader, "IDLE");
+            writer.write_all(format!("+ idling\r\n{} OK IDLE timed out\r\n", tag).as_bytes()).unwrap();
+            let tag = command(reader, "IDLE");
+            writer.write_all(b"+ idling\r\n* 3 EXISTS\r\n").unwrap();
+            assert_eq!(line(reader), "DONE\r\n");
+            writer.write_all(format!("{} OK IDLE done\r\n", tag).as_bytes()).unwrap();
+        },
+                     |mailbox| {
+            let mut idle = mailbox.idle().unwrap();
+            match idle.wait().unwrap() {
+                IdleEvent::Updates(updates) => assert_eq!(updates.len(), 1),
+                event => panic!("{:?}", event),
+            }
+            assert!(idle.done().unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn fails_when_server_refuses_idle() {
+        with_mailbox(|reader, writer| {
+            let tag = command(reader, "IDLE");
+            writer.write_all(format!("+ idling\r\n{} NO IDLE refused\r\n", tag).as_bytes()).unwrap();
+            let tag = command(reader, "CHECK");
+            writer.write_all(format!("{} OK done\r\n", tag).as_bytes()).unwrap();
+        },
+                     |mailbox| {
+            {
+                let mut idle = mailbox.idle().unwrap();
+                match idle.wait() {
+                    Err(IMAPError::No(_)) => {}
+                    result => panic!("{:?}", result),
+                }
+            }
+            // Dropping the handle must not send DONE for an IDLE that has ended
+            mailbox.check().unwrap();
+        });
+    }
+}
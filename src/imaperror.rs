@@ -13,6 +13,18 @@ pub enum IMAPError {
     LoginError(String),
     SelectError(String),
     ConnectError(String),
+    StartTlsError(String),
+    ServerAuthError(String),
+    /// The target mailbox does not exist, but could be created (the `TRYCREATE` response code).
+    TryCreate(String),
+    /// The mailbox already exists (the `ALREADYEXISTS` response code of RFC 5530).
+    AlreadyExists(String),
+    /// The mailbox does not exist (the `NONEXISTENT` response code of RFC 5530).
+    NonExistent(String),
+    /// The command would modify a mailbox that was opened read-only.
+    ReadOnly(String),
+    /// The server does not advertise the capability the command needs.
+    Unsupported(String),
     No(String),
     Bad(String),
     Invalid(String),
@@ -26,6 +38,15 @@ impl fmt::Display for IMAPError {
             IMAPError::LoginError(ref err) => write!(f, "Login error: {}", err),
             IMAPError::SelectError(ref err) => write!(f, "Select error: {}", err),
             IMAPError::ConnectError(ref err) => write!(f, "Connect error: {}", err),
+            IMAPError::StartTlsError(ref err) => write!(f, "STARTTLS error: {}", err),
+            IMAPError::ServerAuthError(ref err) => {
+                write!(f, "Server authentication error: {}", err)
+            }
+            IMAPError::TryCreate(ref err) => write!(f, "Mailbox does not exist: {}", err),
+            IMAPError::AlreadyExists(ref err) => write!(f, "Mailbox already exists: {}", err),
+            IMAPError::NonExistent(ref err) => write!(f, "No such mailbox: {}", err),
+            IMAPError::ReadOnly(ref err) => write!(f, "Read-only mailbox: {}", err),
+            IMAPError::Unsupported(ref err) => write!(f, "Not supported by server: {}", err),
             IMAPError::No(ref err) => write!(f, "IMAP Command returned 'No': {}", err),
             IMAPError::Bad(ref err) => write!(f, "IMAP Command returned 'Bad': {}", err),
             IMAPError::Invalid(ref err) => write!(f, "Invalid IMAP response: {}", err),
@@ -42,6 +63,13 @@ impl error::Error for IMAPError {
             IMAPError::LoginError(ref err) => err,
             IMAPError::SelectError(ref err) => err,
             IMAPError::ConnectError(ref err) => err,
+            IMAPError::StartTlsError(ref err) => err,
+            IMAPError::ServerAuthError(ref err) => err,
+            IMAPError::TryCreate(ref err) => err,
+            IMAPError::AlreadyExists(ref err) => err,
+            IMAPError::NonExistent(ref err) => err,
+            IMAPError::ReadOnly(ref err) => err,
+            IMAPError::Unsupported(ref err) => err,
             IMAPError::No(ref err) => err,
             IMAPError::Bad(ref err) => err,
             IMAPError::Invalid(ref err) => err,
@@ -55,6 +83,13 @@ impl error::Error for IMAPError {
             IMAPError::LoginError(_) => None,
             IMAPError::SelectError(_) => None,
             IMAPError::ConnectError(_) => None,
+            IMAPError::StartTlsError(_) => None,
+            IMAPError::ServerAuthError(_) => None,
+            IMAPError::TryCreate(_) => None,
+            IMAPError::AlreadyExists(_) => None,
+            IMAPError::NonExistent(_) => None,
+            IMAPError::ReadOnly(_) => None,
+            IMAPError::Unsupported(_) => None,
             IMAPError::No(_) => None,
             IMAPError::Bad(_) => None,
             IMAPError::Invalid(_) => None,
@@ -1,18 +1,61 @@
 #![allow(unused_variables, unused_imports, dead_code)]
 extern crate openssl;
+extern crate openssl_verify;
 extern crate rand;
-extern crate regex;
 
-pub mod imaperror;
+/// Like `try!`, but for functions returning an `Option`.
+macro_rules! try_opt {
+    ($e:expr) => (match $e {
+        Some(value) => value,
+        None => return None,
+    })
+}
 
+pub mod authenticator;
+mod base64;
+pub mod capability;
+mod command;
+pub mod date;
+pub mod fetch;
+pub mod flag;
+pub mod folder;
+pub mod idle;
+pub mod imaperror;
+pub mod response;
+pub mod search;
+mod stream;
+mod utf7;
+
+use authenticator::Authenticator;
+use capability::{Capabilities, Capability};
+use command::Command;
+use date::DateTime;
+use fetch::{Attribute, BodySection, Fetch};
+use flag::Flag;
+use folder::Folder;
 use imaperror::IMAPError;
-use openssl::ssl::{SslContext, SslStream, SslMethod, Ssl};
+use openssl::ssl::{SslContext, SslStream, SslMethod, Ssl, SSL_OP_NO_SSLV2, SSL_OP_NO_SSLV3,
+                   SSL_VERIFY_PEER};
 use rand::Rng;
-use regex::Regex;
+use response::{Response, ResponseCode, Status, StatusResponse, UntaggedResponse, Value};
+use search::SearchQuery;
+use std::cmp;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
 use std::io::prelude;
+use std::iter::FromIterator;
+use std::mem;
 use std::io::{Write, Read};
 use std::net::TcpStream;
+use std::ops::{Deref, DerefMut, RangeFrom};
 use std::time::Duration;
+use stream::Stream;
+
+/// How long a read waits for the server before failing, unless changed with
+/// `IMAPConnection::set_read_timeout`.
+const DEFAULT_READ_TIMEOUT_SECS: u64 = 60;
 
 #[derive(Debug)]
 pub enum IMAPConnection {
@@ -30,6 +73,16 @@ pub enum IMAPClient {
     Logout,
 }
 
+/// What `IMAPClient::starttls` should do if the server does not offer `STARTTLS`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StartTlsPolicy {
+    /// Fail rather than continue over an unencrypted connection.
+    Required,
+    /// Carry on without encryption. Credentials sent afterwards can be read by anyone on the
+    /// network path, so only use this where that is acceptable.
+    AllowPlaintext,
+}
+
 #[derive(Debug)]
 struct Tag {
     tag_prefix: String,
@@ -38,40 +91,40 @@ struct Tag {
 
 #[derive(Debug)]
 pub struct MailServer {
-    imap: IMAPConnection,
+    imap: Stream,
     tag: Tag,
 }
 
+/// A selected mailbox. The commands that do not depend on it, such as `list` and `create`, are
+/// those of the `MailServer` it derefs to.
 #[derive(Debug)]
 pub struct Mailbox {
-    imap: IMAPConnection,
-    tag: Tag,
-    flags: String,
+    server: MailServer,
+    flags: Vec<Flag>,
     exists: String,
     recent: String,
     unseen: Option<String>,
-    permanentflags: Option<String>,
+    permanentflags: Option<Vec<Flag>>,
     uidnext: Option<String>,
     uidvalidity: Option<String>,
     permission: Option<String>,
+    read_only: bool,
 }
 
 #[derive(Debug)]
 pub struct MailboxResponse {
-    flags: String,
+    flags: Vec<Flag>,
     exists: String,
     recent: String,
     unseen: Option<String>,
-    permanentflags: Option<String>,
+    permanentflags: Option<Vec<Flag>>,
     uidnext: Option<String>,
     uidvalidity: Option<String>,
     permission: Option<String>,
 }
 
-#[derive(Debug)]
-pub struct Email {
-    full_text: String,
-}
+/// What `Mailbox::fetch` returned before it was typed; see `fetch::Fetch`.
+pub type Email = Fetch;
 
 
 #[derive(Debug)]
@@ -83,7 +136,9 @@ pub enum Macro {
 
 pub enum DataItem {
     Macro(Macro),
-    Raw(String)
+    Raw(String),
+    /// A list of typed attributes, sent in parentheses.
+    Attributes(Vec<Attribute>),
 }
 
 impl From<Macro> for DataItem {
@@ -104,10 +159,222 @@ impl<'a> From<&'a str> for DataItem {
     }
 }
 
+impl From<Vec<Attribute>> for DataItem {
+    fn from(attributes: Vec<Attribute>) -> DataItem {
+        DataItem::Attributes(attributes)
+    }
+}
 
+impl From<Attribute> for DataItem {
+    fn from(attribute: Attribute) -> DataItem {
+        DataItem::Attributes(vec![attribute])
+    }
+}
+
+impl From<BodySection> for DataItem {
+    fn from(section: BodySection) -> DataItem {
+        DataItem::Attributes(vec![Attribute::Section(section)])
+    }
+}
+
+
+/// How `STORE` should change the flags of messages.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StoreMode {
+    /// Add the flags given (`+FLAGS`).
+    Add,
+    /// Remove the flags given (`-FLAGS`).
+    Remove,
+    /// Replace all flags with the ones given (`FLAGS`).
+    Replace,
+}
+
+impl StoreMode {
+    fn item(&self, silent: bool) -> &'static str {
+        match (*self, silent) {
+            (StoreMode::Add, false) => "+FLAGS",
+            (StoreMode::Add, true) => "+FLAGS.SILENT",
+            (StoreMode::Remove, false) => "-FLAGS",
+            (StoreMode::Remove, true) => "-FLAGS.SILENT",
+            (StoreMode::Replace, false) => "FLAGS",
+            (StoreMode::Replace, true) => "FLAGS.SILENT",
+        }
+    }
+}
+
+/// A data item that can be asked for with `STATUS`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StatusItem {
+    Messages,
+    Recent,
+    UidNext,
+    UidValidity,
+    Unseen,
+    /// Only returned by servers that support `CONDSTORE` (RFC 7162).
+    HighestModSeq,
+    /// Only returned by servers that support `STATUS=SIZE` (RFC 8438).
+    Size,
+}
+
+impl StatusItem {
+    /// All the items defined by RFC 3501.
+    pub fn all() -> Vec<StatusItem> {
+        vec![StatusItem::Messages,
+             StatusItem::Recent,
+             StatusItem::UidNext,
+             StatusItem::UidValidity,
+             StatusItem::Unseen]
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            StatusItem::Messages => "MESSAGES",
+            StatusItem::Recent => "RECENT",
+            StatusItem::UidNext => "UIDNEXT",
+            StatusItem::UidValidity => "UIDVALIDITY",
+            StatusItem::Unseen => "UNSEEN",
+            StatusItem::HighestModSeq => "HIGHESTMODSEQ",
+            StatusItem::Size => "SIZE",
+        }
+    }
+}
+
+/// The counters returned by `STATUS`. Items that were not asked for, or that the server does not
+/// support, are `None`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct MailboxStatus {
+    pub messages: Option<u32>,
+    pub recent: Option<u32>,
+    pub uid_next: Option<u32>,
+    pub uid_validity: Option<u32>,
+    pub unseen: Option<u32>,
+    pub highest_mod_seq: Option<u64>,
+    pub size: Option<u64>,
+}
+
+/// The UIDs that copied or moved messages were given in the destination mailbox, from the
+/// `COPYUID` response code of UIDPLUS (RFC 4315).
+#[derive(Debug, Clone, PartialEq)]
+pub struct CopyUid {
+    /// The `UIDVALIDITY` of the destination mailbox.
+    pub uid_validity: u32,
+    /// Maps the UID of each source message to its UID in the destination mailbox.
+    pub uids: HashMap<u32, u32>,
+}
+
+impl CopyUid {
+    /// Parses the arguments of `COPYUID`: the `UIDVALIDITY`, then the source and destination
+    /// UID sets, which list the messages in the same order.
+    fn parse(args: &str) -> Option<CopyUid> {
+        let mut args = args.split(' ');
+        let uid_validity = try_opt!(try_opt!(args.next()).parse().ok());
+        let source = try_opt!(CopyUid::uid_set(try_opt!(args.next())));
+        let destination = try_opt!(CopyUid::uid_set(try_opt!(args.next())));
+
+        if source.len() != destination.len() {
+            return None;
+        }
+
+        Some(CopyUid {
+            uid_validity: uid_validity,
+            uids: source.into_iter().zip(destination).collect(),
+        })
+    }
+
+    /// Expands a set of UIDs such as `4,7:9`, keeping the order in which they are listed.
+    fn uid_set(set: &str) -> Option<Vec<u32>> {
+        let mut uids = Vec::new();
+
+        for range in set.split(',') {
+            let mut ends = range.splitn(2, ':');
+            let start: u32 = try_opt!(try_opt!(ends.next()).parse().ok());
+            let end: u32 = match ends.next() {
+                Some(end) => try_opt!(end.parse().ok()),
+                None => start,
+            };
+
+            // A range may be given either way round, but always stands for the UIDs in order
+            uids.extend(cmp::min(start, end)..=cmp::max(start, end));
+        }
+
+        Some(uids)
+    }
+}
+
+/// The UID an appended message was given, from the `APPENDUID` response code of UIDPLUS
+/// (RFC 4315).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AppendUid {
+    /// The `UIDVALIDITY` of the mailbox appended to.
+    pub uid_validity: u32,
+    pub uid: u32,
+}
+
+/// A set of message sequence numbers, as used by `FETCH` and other commands.
+///
+/// Sets built from an iterator of numbers are sorted and compressed into ranges, so
+/// `vec![1, 2, 3, 7, 9, 10]` is sent as `1:3,7,9:10`.
+#[derive(Debug, Clone, PartialEq)]
 pub enum SequenceSet {
+    /// The range `l:h`.
     Set(u32, u32),
+    /// The single number `n`.
     Atom(u32),
+    /// The open range `n:*`, from `n` to the largest number in use.
+    Open(u32),
+    /// `*`, the largest number in use.
+    Last,
+    /// A comma separated list of sets.
+    List(Vec<SequenceSet>),
+}
+
+impl SequenceSet {
+    pub fn is_empty(&self) -> bool {
+        match *self {
+            SequenceSet::List(ref sets) => sets.iter().all(|set| set.is_empty()),
+            _ => false,
+        }
+    }
+
+    /// The numbers in the set, in the order they are listed. Open ranges and `*` are left out,
+    /// as only the server knows where they end.
+    pub fn numbers(&self) -> Vec<u32> {
+        match *self {
+            SequenceSet::Set(l, h) if l <= h => (l..h).chain(Some(h)).collect(),
+            SequenceSet::Set(l, h) => (h..l).chain(Some(l)).rev().collect(),
+            SequenceSet::Atom(a) => vec![a],
+            SequenceSet::Open(_) | SequenceSet::Last => Vec::new(),
+            SequenceSet::List(ref sets) => sets.iter().flat_map(|set| set.numbers()).collect(),
+        }
+    }
+
+    fn range(start: u32, end: u32) -> SequenceSet {
+        if start == end {
+            SequenceSet::Atom(start)
+        } else {
+            SequenceSet::Set(start, end)
+        }
+    }
+}
+
+impl fmt::Display for SequenceSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SequenceSet::Set(l, h) => write!(f, "{}:{}", l, h),
+            SequenceSet::Atom(a) => write!(f, "{}", a),
+            SequenceSet::Open(l) => write!(f, "{}:*", l),
+            SequenceSet::Last => write!(f, "*"),
+            SequenceSet::List(ref sets) => {
+                for (i, set) in sets.iter().enumerate() {
+                    if i > 0 {
+                        try!(write!(f, ","));
+                    }
+                    try!(write!(f, "{}", set));
+                }
+                Ok(())
+            }
+        }
+    }
 }
 
 impl From<(u32, u32)> for SequenceSet {
@@ -122,6 +389,100 @@ impl From<u32> for SequenceSet {
     }
 }
 
+impl From<RangeFrom<u32>> for SequenceSet {
+    fn from(r: RangeFrom<u32>) -> SequenceSet {
+        SequenceSet::Open(r.start)
+    }
+}
+
+impl From<Vec<u32>> for SequenceSet {
+    fn from(v: Vec<u32>) -> SequenceSet {
+        v.into_iter().collect()
+    }
+}
+
+impl<'a> From<&'a [u32]> for SequenceSet {
+    fn from(v: &[u32]) -> SequenceSet {
+        v.iter().cloned().collect()
+    }
+}
+
+impl FromIterator<u32> for SequenceSet {
+    fn from_iter<I: IntoIterator<Item = u32>>(iter: I) -> SequenceSet {
+        let mut numbers: Vec<u32> = iter.into_iter().collect();
+        numbers.sort();
+        numbers.dedup();
+
+        let mut sets = Vec::new();
+        let mut numbers = numbers.into_iter();
+
+        if let Some(first) = numbers.next() {
+            let (mut start, mut end) = (first, first);
+
+            for n in numbers {
+                if n == end + 1 {
+                    end = n;
+                    continue;
+                }
+                sets.push(SequenceSet::range(start, end));
+                start = n;
+                end = n;
+            }
+            sets.push(SequenceSet::range(start, end));
+        }
+
+        if sets.len() == 1 {
+            sets.pop().unwrap()
+        } else {
+            SequenceSet::List(sets)
+        }
+    }
+}
+
+/// A set of message UIDs, as taken by the `uid_` methods of `Mailbox`. It is a separate type
+/// from `SequenceSet` so that UIDs and sequence numbers can not be passed in place of each
+/// other: nothing converts into one implicitly, so it is built with `single`, `range`,
+/// `range_from` or `from_uids`, or comes from `uid_search`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UidSet(SequenceSet);
+
+impl UidSet {
+    /// The single UID `uid`.
+    pub fn single(uid: u32) -> UidSet {
+        UidSet(SequenceSet::Atom(uid))
+    }
+
+    /// The UIDs from `first` to `last`.
+    pub fn range(first: u32, last: u32) -> UidSet {
+        UidSet(SequenceSet::from((first, last)))
+    }
+
+    /// The UIDs from `first` to the highest in the mailbox (`first:*`).
+    pub fn range_from(first: u32) -> UidSet {
+        UidSet(SequenceSet::from(first..))
+    }
+
+    /// The set of `uids`, sorted and compressed into ranges like a `SequenceSet`.
+    pub fn from_uids<I: IntoIterator<Item = u32>>(uids: I) -> UidSet {
+        UidSet(uids.into_iter().collect())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The UIDs in the set, as for `SequenceSet::numbers`.
+    pub fn uids(&self) -> Vec<u32> {
+        self.0.numbers()
+    }
+}
+
+impl fmt::Display for UidSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
 impl IMAPConnection {
 
     pub fn new() -> IMAPConnection {
@@ -135,7 +496,7 @@ impl IMAPConnection {
         let server = host + ":" + &port.to_string();
 
         let stream = try!(TcpStream::connect(&*server));
-        let _ = try!(stream.set_read_timeout(Some(Duration::from_secs(2))));
+        let _ = try!(stream.set_read_timeout(Some(Duration::from_secs(DEFAULT_READ_TIMEOUT_SECS))));
         let _ = try!(stream.set_write_timeout(Some(Duration::from_secs(2))));
 
         Ok(IMAPConnection::Basic(stream))
@@ -145,22 +506,107 @@ impl IMAPConnection {
                                              port: u32)
                                              -> Result<IMAPConnection, IMAPError> {
         let host = host.into();
-        let server = host + ":" + &port.to_string();
+        let server = host.clone() + ":" + &port.to_string();
 
         let stream = {
             let stream = try!(TcpStream::connect(&*server));
-            let _ = try!(stream.set_read_timeout(Some(Duration::from_secs(2))));
+            let timeout = Duration::from_secs(DEFAULT_READ_TIMEOUT_SECS);
+            let _ = try!(stream.set_read_timeout(Some(timeout)));
             let _ = try!(stream.set_write_timeout(Some(Duration::from_secs(2))));
 
-            let sslcontext = try!(SslContext::new(SslMethod::Sslv23));
-            let ssl = try!(Ssl::new(&sslcontext));
-            let stream = try!(SslStream::connect(ssl, stream));
-
-            stream
+            try!(IMAPConnection::wrap_tls(stream, &host))
         };
 
         Ok(IMAPConnection::Ssl(stream))
     }
+
+    /// Upgrades a `Basic` connection to `Ssl` with the server `domain`, leaving it
+    /// `Disconnected` if the TLS handshake fails. An `Ssl` connection is left as it is.
+    fn upgrade_tls(&mut self, domain: &str) -> Result<(), IMAPError> {
+        match mem::replace(self, IMAPConnection::Disconnected) {
+            IMAPConnection::Basic(stream) => {
+                *self = IMAPConnection::Ssl(try!(IMAPConnection::wrap_tls(stream, domain)));
+                Ok(())
+            }
+            IMAPConnection::Ssl(stream) => {
+                *self = IMAPConnection::Ssl(stream);
+                Ok(())
+            }
+            IMAPConnection::Disconnected =>
+                Err(IMAPError::ConnectError("Not connected to server.".to_owned())),
+        }
+    }
+
+    /// Sets how long a read waits for the server before the command fails, so that a stalled
+    /// server or a dropped connection does not block forever. `None` waits indefinitely.
+    ///
+    /// Connections made with `new_notls` and `new_tls` wait a minute. This is the time allowed
+    /// for each read rather than for a whole response, so long responses are not cut short.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            &IMAPConnection::Basic(ref stream) => stream.set_read_timeout(timeout),
+            &IMAPConnection::Ssl(ref stream) => stream.get_ref().set_read_timeout(timeout),
+            &IMAPConnection::Disconnected =>
+                Err(io::Error::new(io::ErrorKind::NotConnected, "Not connected to server.")),
+        }
+    }
+
+    fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        match self {
+            &IMAPConnection::Basic(ref stream) => stream.read_timeout(),
+            &IMAPConnection::Ssl(ref stream) => stream.get_ref().read_timeout(),
+            &IMAPConnection::Disconnected =>
+                Err(io::Error::new(io::ErrorKind::NotConnected, "Not connected to server.")),
+        }
+    }
+
+    /// Starts TLS on `stream`, failing unless the server's certificate is trusted by the system
+    /// and issued for `domain`.
+    fn wrap_tls(stream: TcpStream, domain: &str) -> Result<SslStream<TcpStream>, IMAPError> {
+        let mut sslcontext = try!(SslContext::new(SslMethod::Sslv23));
+        try!(sslcontext.set_default_verify_paths());
+        sslcontext.set_options(SSL_OP_NO_SSLV2 | SSL_OP_NO_SSLV3);
+
+        let mut ssl = try!(Ssl::new(&sslcontext));
+        try!(ssl.set_hostname(domain));
+        let domain = domain.to_owned();
+        ssl.set_verify_callback(SSL_VERIFY_PEER, move |preverified, x509_ctx| {
+            openssl_verify::verify_callback(&domain, preverified, x509_ctx)
+        });
+        let stream = try!(SslStream::connect(ssl, stream));
+
+        Ok(stream)
+    }
+}
+
+impl Read for IMAPConnection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            &mut IMAPConnection::Basic(ref mut stream) => stream.read(buf),
+            &mut IMAPConnection::Ssl(ref mut stream) => stream.read(buf),
+            &mut IMAPConnection::Disconnected =>
+                Err(io::Error::new(io::ErrorKind::NotConnected, "Not connected to server.")),
+        }
+    }
+}
+
+impl Write for IMAPConnection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            &mut IMAPConnection::Basic(ref mut stream) => stream.write(buf),
+            &mut IMAPConnection::Ssl(ref mut stream) => stream.write(buf),
+            &mut IMAPConnection::Disconnected =>
+                Err(io::Error::new(io::ErrorKind::NotConnected, "Not connected to server.")),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            &mut IMAPConnection::Basic(ref mut stream) => stream.flush(),
+            &mut IMAPConnection::Ssl(ref mut stream) => stream.flush(),
+            &mut IMAPConnection::Disconnected => Ok(()),
+        }
+    }
 }
 
 impl Tag {
@@ -189,39 +635,89 @@ impl Tag {
 
 impl IMAPClient {
     pub fn connect(imap: IMAPConnection) -> Result<IMAPClient, IMAPError> {
-        let mut imap = imap;
+        if let IMAPConnection::Disconnected = imap {
+            return Err(IMAPError::ConnectError("Can not connect, IMAPConection in \
+                                                Disconnected state"
+                                                   .to_owned()));
+        }
 
-        let stream = match &mut imap {
-            &mut IMAPConnection::Basic(ref mut stream) => try!(IMAPClient::read_greeting(stream)),
-            &mut IMAPConnection::Ssl(ref mut stream) => try!(IMAPClient::read_greeting(stream)),
-            &mut IMAPConnection::Disconnected =>
-                return Err(IMAPError::ConnectError("Can not connect, IMAPConection in \
-                                                    Disconnected state"
-                                                       .to_owned())),
-        };
+        let mut imap = Stream::new(imap);
+        let greeting = try!(IMAPClient::read_greeting(&mut imap));
+        imap.set_capabilities(IMAPClient::find_capabilities(&[], &greeting));
 
-        let unauthenticated = MailServer {
+        let server = MailServer {
             imap: imap,
             tag: Tag::new(),
         };
 
-        Ok(IMAPClient::UnAuthenticated(unauthenticated))
+        // A PREAUTH greeting means the connection has already been authenticated
+        match greeting.status {
+            Status::PreAuth => Ok(IMAPClient::Authenticated(server)),
+            _ => Ok(IMAPClient::UnAuthenticated(server)),
+        }
+    }
+
+    /// Upgrades a plaintext connection to TLS with the `STARTTLS` command. The server's
+    /// certificate has to be trusted by the system and issued for `domain`, the name the server
+    /// was connected to.
+    ///
+    /// If the server does not advertise `STARTTLS` this fails, unless `policy` is
+    /// `StartTlsPolicy::AllowPlaintext`, in which case the client is returned unchanged.
+    pub fn starttls(self,
+                    domain: &str,
+                    policy: StartTlsPolicy)
+                    -> Result<IMAPClient, (IMAPClient, IMAPError)> {
+        match self {
+            IMAPClient::UnAuthenticated(mut server) => {
+                let supported = match server.capabilities() {
+                    Ok(capabilities) => capabilities.has(&Capability::StartTls),
+                    Err(e) => return Err((IMAPClient::UnAuthenticated(server), e)),
+                };
+
+                if !supported {
+                    return match policy {
+                        StartTlsPolicy::AllowPlaintext => Ok(IMAPClient::UnAuthenticated(server)),
+                        StartTlsPolicy::Required => {
+                            Err((IMAPClient::UnAuthenticated(server),
+                                 IMAPError::StartTlsError("Server does not advertise STARTTLS"
+                                                              .to_owned())))
+                        }
+                    };
+                }
+
+                let tag = server.tag.next_tag();
+                let res = server.command(&tag, &Command::new("STARTTLS"))
+                                .and_then(|res| IMAPClient::check_tagged_response(&res, &tag))
+                                .and_then(|_| server.imap.upgrade_tls(domain));
+                match res {
+                    Ok(_) => Ok(IMAPClient::UnAuthenticated(server)),
+                    Err(e) => Err((IMAPClient::UnAuthenticated(server), e)),
+                }
+            }
+            client => {
+                Err((client,
+                     IMAPError::StartTlsError("STARTTLS is only valid before authenticating"
+                                                  .to_owned())))
+            }
+        }
     }
 
     pub fn login<IntoString: Into<String>>(self,
                                            username: IntoString,
                                            password: IntoString)
                                            -> Result<IMAPClient, (IMAPClient, IMAPError)> {
-        let cmd = format!("LOGIN {} {}", username.into(), password.into());
+        let cmd = Command::new("LOGIN").arg(username.into()).arg(password.into());
 
         match self {
             IMAPClient::UnAuthenticated(mut server) => {
                 let tag = server.tag.next_tag();
-                let cmd = format!("{} {}\r\n", tag, cmd);
-                match server.command(&cmd) {
+                match server.command(&tag, &cmd) {
                     Ok(res) => {
-                        match IMAPClient::check_tagged_response(res, &tag) {
-                            Ok(_) => Ok(IMAPClient::Authenticated(server)),
+                        match IMAPClient::check_authenticated_response(&res, &tag) {
+                            Ok(capabilities) => {
+                                server.imap.set_capabilities(capabilities);
+                                Ok(IMAPClient::Authenticated(server))
+                            }
                             Err(e) => Err((IMAPClient::UnAuthenticated(server), e)),
                         }
                     }
@@ -232,9 +728,8 @@ impl IMAPClient {
                 Ok(IMAPClient::Authenticated(server))
             }
             IMAPClient::Selected(mut mailbox) => {
-                let tag = mailbox.tag.next_tag();
-                let cmd = format!("{} {}\r\n", tag, cmd);
-                match mailbox.command(&cmd) {
+                let tag = mailbox.server.tag.next_tag();
+                match mailbox.server.command(&tag, &cmd) {
                     Ok(_) => Ok(IMAPClient::Selected(mailbox)),
                     Err(e) => Err((IMAPClient::Selected(mailbox), e)),
                 }
@@ -247,69 +742,108 @@ impl IMAPClient {
 
     }
 
+    /// Authenticates with the SASL mechanism implemented by `authenticator`, using the
+    /// `AUTHENTICATE` command.
+    pub fn authenticate<A: Authenticator + ?Sized>(self,
+                                                   authenticator: &mut A)
+                                                   -> Result<IMAPClient, (IMAPClient, IMAPError)> {
+        match self {
+            IMAPClient::UnAuthenticated(mut server) => {
+                let tag = server.tag.next_tag();
+                let cmd = Command::new("AUTHENTICATE").arg(authenticator.mechanism());
+                let res = server.imap.interact(&tag, &cmd, |continuation| {
+                    let challenge = try!(IMAPClient::decode_challenge(continuation));
+                    let response = try!(authenticator.process(&challenge));
+                    Ok(base64::encode(&response).into_bytes())
+                });
+
+                match res.and_then(|res| IMAPClient::check_authenticated_response(&res, &tag)) {
+                    Ok(capabilities) => {
+                        server.imap.set_capabilities(capabilities);
+                        match authenticator.complete() {
+                            Ok(_) => Ok(IMAPClient::Authenticated(server)),
+                            Err(e) => {
+                                // The server could not prove who it is, so it can not be trusted
+                                // with anything sent over this connection
+                                let _ = IMAPClient::Authenticated(server).logout();
+                                Err((IMAPClient::Logout, e))
+                            }
+                        }
+                    }
+                    Err(e) => Err((IMAPClient::UnAuthenticated(server), e)),
+                }
+            }
+            IMAPClient::Authenticated(server) => Ok(IMAPClient::Authenticated(server)),
+            IMAPClient::Selected(mailbox) => Ok(IMAPClient::Selected(mailbox)),
+            IMAPClient::Logout => {
+                Err((IMAPClient::Logout,
+                     IMAPError::LoginError("Not valid to try to authenticate after Logout"
+                                               .to_owned())))
+            }
+        }
+    }
+
     pub fn select<IntoString: Into<String>>(self,
                                             mailbox_name: IntoString)
                                             -> Result<IMAPClient, (IMAPClient, IMAPError)> {
-        let cmd = format!("SELECT {}", mailbox_name.into());
+        self.open_mailbox("SELECT", &mailbox_name.into())
+    }
+
+    /// Like `select`, but opens the mailbox read-only, so that neither `\Recent` nor `\Seen`
+    /// change. Commands that would modify the mailbox are refused without asking the server.
+    pub fn examine<IntoString: Into<String>>(self,
+                                             mailbox_name: IntoString)
+                                             -> Result<IMAPClient, (IMAPClient, IMAPError)> {
+        self.open_mailbox("EXAMINE", &mailbox_name.into())
+    }
+
+    /// Sends `SELECT` or `EXAMINE`. A failed attempt from the selected state leaves no mailbox
+    /// selected, unless the server rejected the command outright with `BAD`.
+    fn open_mailbox(self,
+                    command: &str,
+                    mailbox_name: &str)
+                    -> Result<IMAPClient, (IMAPClient, IMAPError)> {
+        let cmd = Command::new(command).arg(utf7::encode(mailbox_name));
+        let read_only = command == "EXAMINE";
 
         match self {
             IMAPClient::UnAuthenticated(server) => {
                 Err((IMAPClient::UnAuthenticated(server),
-                     IMAPError::SelectError("Must authenticate before SELECT".to_owned())))
+                     IMAPError::SelectError(format!("Must authenticate before {}", command))))
             }
             IMAPClient::Authenticated(mut server) => {
                 let tag = server.tag.next_tag();
-                let cmd = format!("{} {}\r\n", tag, cmd);
-                match server.command(&cmd) {
-                    Ok(res) => {
-                        match IMAPClient::check_select_response(&res, &tag) {
-                            Ok(mailres) => {
-                                let mailbox = Mailbox {
-                                    imap: server.imap,
-                                    tag: server.tag,
-                                    flags: mailres.flags,
-                                    exists: mailres.exists,
-                                    recent: mailres.recent,
-                                    unseen: mailres.unseen,
-                                    permanentflags: mailres.permanentflags,
-                                    uidnext: mailres.uidnext,
-                                    uidvalidity: mailres.uidvalidity,
-                                    permission: mailres.permission,
-                                };
-                                Ok(IMAPClient::Selected(mailbox))
-                            }
-                            Err(e) => Err((IMAPClient::UnAuthenticated(server), e)),
-                        }
+                match server.command(&tag, &cmd)
+                            .and_then(|res| IMAPClient::check_select_response(&res, &tag)) {
+                    Ok(mailres) => {
+                        Ok(IMAPClient::Selected(Mailbox::new(server, mailres, read_only)))
                     }
                     Err(e) => Err((IMAPClient::Authenticated(server), e)),
                 }
             }
             IMAPClient::Selected(mut mailbox) => {
-                let tag = mailbox.tag.next_tag();
-                let cmd = format!("{} {}\r\n", tag, cmd);
-                match mailbox.command(&cmd) {
-                    Ok(res) => {
-                        match IMAPClient::check_response(res) {
-                            Ok(_) => {
-                                Ok(IMAPClient::Selected(mailbox))
-                            }
-                            Err(e) => {
-                                Err((IMAPClient::Selected(mailbox), e))
-                            }
-                        }
+                let tag = mailbox.server.tag.next_tag();
+                match mailbox.server.command(&tag, &cmd)
+                                    .and_then(|res| IMAPClient::check_select_response(&res, &tag)) {
+                    Ok(mailres) => {
+                        Ok(IMAPClient::Selected(Mailbox::new(mailbox.server,
+                                                             mailres,
+                                                             read_only)))
                     }
-                    Err(e) => Err((IMAPClient::Selected(mailbox), e)),
+                    Err(e @ IMAPError::Bad(_)) |
+                    Err(e @ IMAPError::IOError(_)) => Err((IMAPClient::Selected(mailbox), e)),
+                    Err(e) => Err((IMAPClient::Authenticated(mailbox.server), e)),
                 }
             }
             IMAPClient::Logout => {
                 Err((IMAPClient::Logout,
-                     IMAPError::SelectError("Can not select in Logout state".to_owned())))
+                     IMAPError::SelectError(format!("Can not {} in Logout state", command))))
             }
         }
     }
 
     pub fn logout(self) -> Result<IMAPClient, (IMAPClient, IMAPError)> {
-        let cmd = format!("LOGOUT");
+        let cmd = Command::new("LOGOUT");
 
         match self {
             IMAPClient::UnAuthenticated(server) => {
@@ -317,16 +851,14 @@ impl IMAPClient {
             }
             IMAPClient::Authenticated(mut server) => {
                 let tag = server.tag.next_tag();
-                let cmd = format!("{} {}\r\n", tag, cmd);
-                match server.command(&cmd) {
+                match server.command(&tag, &cmd) {
                     Ok(_) => Ok(IMAPClient::Logout),
                     Err(e) => Err((IMAPClient::Authenticated(server), e)),
                 }
             }
             IMAPClient::Selected(mut mailbox) => {
-                let tag = mailbox.tag.next_tag();
-                let cmd = format!("{} {}\r\n", tag, cmd);
-                match mailbox.command(&cmd) {
+                let tag = mailbox.server.tag.next_tag();
+                match mailbox.server.command(&tag, &cmd) {
                     Ok(_) => Ok(IMAPClient::Logout),
                     Err(e) => Err((IMAPClient::Selected(mailbox), e)),
                 }
@@ -337,61 +869,108 @@ impl IMAPClient {
         }
     }
 
-    fn read_greeting<T: Read + Write>(stream: &mut T) -> Result<String, IMAPError> {
-
-        let mut buf = String::new();
-        let _ = stream.read_to_string(&mut buf);
-        println!("{}", buf);
-        let buf = try!(IMAPClient::check_response(buf));
-        Ok(buf)
+    /// Closes the selected mailbox, removing any messages flagged `\Deleted` unless it was
+    /// opened read-only, and returns to the authenticated state.
+    pub fn close(self) -> Result<IMAPClient, (IMAPClient, IMAPError)> {
+        self.leave_mailbox("CLOSE")
     }
 
-    fn capture_response(response: &str, re: Regex) -> Result<String, IMAPError> {
-        let cap = re.captures_iter(response).next();
+    /// Like `close`, but never removes messages. Needs a server that supports `UNSELECT`
+    /// (RFC 3691).
+    pub fn unselect(self) -> Result<IMAPClient, (IMAPClient, IMAPError)> {
+        self.leave_mailbox("UNSELECT")
+    }
 
-        let exists = match cap {
-            Some(cap) => cap.at(1),
-            None => return Err(IMAPError::Invalid("Could not find required response command"
-                                                      .to_owned())),
+    fn leave_mailbox(self, command: &str) -> Result<IMAPClient, (IMAPClient, IMAPError)> {
+        let mut mailbox = match self {
+            IMAPClient::Selected(mailbox) => mailbox,
+            client => {
+                let e = IMAPError::SelectError(format!("No mailbox is selected to {}", command));
+                return Err((client, e));
+            }
         };
 
-        match exists {
-            Some(value) => Ok(value.to_owned()),
-            None =>
-                Err(IMAPError::Invalid("Could not find required response for command".to_owned())),
+        if command == "UNSELECT" {
+            let supported = mailbox.capabilities().map(|c| c.has(&Capability::Unselect));
+            match supported {
+                Ok(true) => {}
+                Ok(false) => {
+                    let e = IMAPError::Unsupported("UNSELECT".to_owned());
+                    return Err((IMAPClient::Selected(mailbox), e));
+                }
+                Err(e) => return Err((IMAPClient::Selected(mailbox), e)),
+            }
+        }
+
+        let cmd = Command::new(command);
+        match IMAPClient::simple_command(&mut mailbox.server.imap,
+                                         &mut mailbox.server.tag,
+                                         &cmd) {
+            Ok(()) => Ok(IMAPClient::Authenticated(mailbox.server)),
+            Err(e) => Err((IMAPClient::Selected(mailbox), e)),
         }
     }
 
-    fn check_select_response(response: &str, tag: &str) -> Result<MailboxResponse, IMAPError> {
-        let existsre = Regex::new(r"(\d+) EXISTS\r\n").unwrap();
-        let recentre = Regex::new(r"(\d+) RECENT\r\n").unwrap();
-        let flagsre = Regex::new(r"FLAGS \(([^\)]+)\)").unwrap();
-        let unseenre = Regex::new(r"\* OK \[UNSEEN (\d+)\]").unwrap();
-        let permanentflagsre = Regex::new(r"PERMANENTFLAGS \(([^\)]+)\)").unwrap();
-        let uidnextre = Regex::new(r"\* OK \[UIDNEXT (\d+)\]").unwrap();
-        let uidvalidityre = Regex::new(r"\* OK \[UIDVALIDITY (\d+)\]").unwrap();
-        let permissionre = Regex::new(r" OK \[([^\]]+)\] SELECT").unwrap();
+    /// Returns the capabilities of the server, asking for them if none are cached.
+    pub fn capabilities(&mut self) -> Result<Capabilities, IMAPError> {
+        match self {
+            &mut IMAPClient::UnAuthenticated(ref mut server) |
+            &mut IMAPClient::Authenticated(ref mut server) => server.capabilities().map(Clone::clone),
+            &mut IMAPClient::Selected(ref mut mailbox) => mailbox.capabilities().map(Clone::clone),
+            &mut IMAPClient::Logout =>
+                Err(IMAPError::ConnectError("Not connected to server.".to_owned())),
+        }
+    }
 
-        let exists = try!(IMAPClient::capture_response(response, existsre));
-        let recent = try!(IMAPClient::capture_response(response, recentre));
-        let flags = try!(IMAPClient::capture_response(response, flagsre));
+    fn read_greeting(stream: &mut Stream) -> Result<StatusResponse, IMAPError> {
+        let buf = try!(stream.read_greeting());
+        IMAPClient::check_response(&buf)
+    }
 
-        let unseen = IMAPClient::capture_response(response, unseenre).ok();
-        let permanentflags = IMAPClient::capture_response(response, permanentflagsre).ok();
-        let uidnext = IMAPClient::capture_response(response, uidnextre).ok();
-        let uidvalidity = IMAPClient::capture_response(response, uidvalidityre).ok();
-        let mut permission = None;
+    fn check_select_response(response: &[Vec<u8>], tag: &str) -> Result<MailboxResponse, IMAPError> {
+        let (untagged, completion) = try!(IMAPClient::parse_tagged_response(response, tag));
+        let missing = |item: &str| IMAPError::Invalid(format!("SELECT response is missing {}", item));
+
+        let mut exists = None;
+        let mut recent = None;
+        let mut flags = None;
+        let mut unseen = None;
+        let mut permanentflags = None;
+        let mut uidnext = None;
+        let mut uidvalidity = None;
+
+        for data in untagged {
+            match data {
+                UntaggedResponse::Exists(n) => exists = Some(n.to_string()),
+                UntaggedResponse::Recent(n) => recent = Some(n.to_string()),
+                UntaggedResponse::Flags(f) => flags = Some(IMAPClient::parse_flags(&f)),
+                UntaggedResponse::Status(StatusResponse { code: Some(code), .. }) => {
+                    match code {
+                        ResponseCode::Unseen(n) => unseen = Some(n.to_string()),
+                        ResponseCode::PermanentFlags(f) => {
+                            permanentflags = Some(IMAPClient::parse_flags(&f))
+                        }
+                        ResponseCode::UidNext(n) => uidnext = Some(n.to_string()),
+                        ResponseCode::UidValidity(n) => uidvalidity = Some(n.to_string()),
+                        _ => {}
+                    }
+                }
+                _ => {}
+            }
+        }
 
-        let tagged_ok = tag.to_owned() + " OK";
+        let permission = match completion.code {
+            Some(ResponseCode::ReadOnly) => Some("READ-ONLY".to_owned()),
+            Some(ResponseCode::ReadWrite) => Some("READ-WRITE".to_owned()),
+            _ => None,
+        };
+
+        try!(IMAPClient::check_status(completion));
 
-        if let Some(index) = response.find(&tagged_ok) {
-            let view = &response[index + tag.len()..];
-            permission = IMAPClient::capture_response(view, permissionre).ok();
-        }
         Ok(MailboxResponse {
-            exists: exists,
-            recent: recent,
-            flags: flags,
+            exists: try!(exists.ok_or_else(|| missing("EXISTS"))),
+            recent: try!(recent.ok_or_else(|| missing("RECENT"))),
+            flags: try!(flags.ok_or_else(|| missing("FLAGS"))),
             unseen: unseen,
             permanentflags: permanentflags,
             uidnext: uidnext,
@@ -400,179 +979,1033 @@ impl IMAPClient {
         })
     }
 
-    fn check_response(response: String) -> Result<String, IMAPError> {
-        if response.len() < 4 {
-            return Err(IMAPError::Invalid(response));
+    /// Checks the server greeting, which must be an untagged `OK` or `PREAUTH`.
+    fn check_response(response: &[u8]) -> Result<StatusResponse, IMAPError> {
+        match try!(response::parse(response)) {
+            Response::Untagged(UntaggedResponse::Status(status)) => {
+                match status.status {
+                    Status::Ok | Status::PreAuth => Ok(status),
+                    Status::Bye => Err(IMAPError::ConnectError(status.text)),
+                    Status::No => Err(IMAPError::No(status.text)),
+                    Status::Bad => Err(IMAPError::Bad(status.text)),
+                }
+            }
+            _ => Err(IMAPError::Invalid(String::from_utf8_lossy(response).into_owned())),
         }
-        let view: &[u8] = &response.as_bytes()[0..4];
+    }
 
-        match view {
-            b"* OK" => return Ok(response.to_owned()),
-            b"* NO" => return Err(IMAPError::No(response.to_owned())),
-            b"* BA" => return Err(IMAPError::Bad(response.to_owned())),
-            _ => return Err(IMAPError::Invalid(response.to_owned())),
+    /// Parses the responses read for the command tagged `tag` into the untagged data the server
+    /// sent and the tagged completion, whatever its status.
+    fn parse_tagged_response(response: &[Vec<u8>],
+                             tag: &str)
+                             -> Result<(Vec<UntaggedResponse>, StatusResponse), IMAPError> {
+        let mut untagged = Vec::new();
+
+        for raw in response {
+            match try!(response::parse(raw)) {
+                Response::Untagged(data) => untagged.push(data),
+                Response::Tagged(ref t, ref completion) if t == tag => {
+                    return Ok((untagged, completion.clone()))
+                }
+                _ => {}
+            }
         }
+
+        Err(IMAPError::Invalid(format!("No tagged completion for {}", tag)))
     }
 
-    fn check_tagged_response(response: String, tag: &str) -> Result<String, IMAPError> {
-        if response.len() < tag.len() {
-            return Err(IMAPError::Invalid(response));
+    /// Like `parse_tagged_response`, but turns a `NO` or `BAD` completion into an error.
+    fn check_tagged_response(response: &[Vec<u8>],
+                             tag: &str)
+                             -> Result<Vec<UntaggedResponse>, IMAPError> {
+        let (untagged, completion) = try!(IMAPClient::parse_tagged_response(response, tag));
+        try!(IMAPClient::check_status(completion));
+        Ok(untagged)
+    }
+
+    /// Sends `STATUS` for `mailbox`, leaving out any items the server does not support.
+    fn mailbox_status(imap: &mut Stream,
+                      tag: &mut Tag,
+                      mailbox: &str,
+                      items: &[StatusItem])
+                      -> Result<MailboxStatus, IMAPError> {
+        let names: Vec<&str> = {
+            let capabilities = try!(IMAPClient::capability(imap, tag));
+            items.iter()
+                 .filter(|item| match **item {
+                     StatusItem::HighestModSeq => capabilities.has_str("CONDSTORE"),
+                     StatusItem::Size => capabilities.has_str("STATUS=SIZE"),
+                     _ => true,
+                 })
+                 .map(StatusItem::name)
+                 .collect()
+        };
+
+        let mut status = MailboxStatus::default();
+        if names.is_empty() {
+            return Ok(status);
         }
 
-        let view: &[u8] = &response.as_bytes()[0..tag.len()];
+        let tag = tag.next_tag();
+        let cmd = Command::new("STATUS")
+                      .arg(utf7::encode(mailbox))
+                      .raw(&format!("({})", names.join(" ")));
+        let res = try!(imap.command(&tag, &cmd));
+        let untagged = try!(IMAPClient::check_tagged_response(&res, &tag));
+
+        for data in untagged {
+            if let UntaggedResponse::MailboxStatus(_, items) = data {
+                for (name, value) in items {
+                    let number = || {
+                        u32::try_from(value).map_err(|_| {
+                            IMAPError::Invalid(format!("STATUS {} {} is out of range", name, value))
+                        })
+                    };
+                    match &name[..] {
+                        "MESSAGES" => status.messages = Some(try!(number())),
+                        "RECENT" => status.recent = Some(try!(number())),
+                        "UIDNEXT" => status.uid_next = Some(try!(number())),
+                        "UIDVALIDITY" => status.uid_validity = Some(try!(number())),
+                        "UNSEEN" => status.unseen = Some(try!(number())),
+                        "HIGHESTMODSEQ" => status.highest_mod_seq = Some(value),
+                        "SIZE" => status.size = Some(value),
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        Ok(status)
+    }
 
-        if view == tag.as_bytes() {
-            let view: &[u8] = &response.as_bytes()[tag.len()..tag.len() + 3];
+    /// Sends a command that returns nothing but its completion.
+    fn simple_command(imap: &mut Stream, tag: &mut Tag, cmd: &Command) -> Result<(), IMAPError> {
+        let tag = tag.next_tag();
+        let res = try!(imap.command(&tag, cmd));
+        try!(IMAPClient::check_tagged_response(&res, &tag));
+        Ok(())
+    }
 
-            match view {
-                b" OK" => Ok(response.to_owned()),
-                b" NO" => Err(IMAPError::No(response.to_owned())),
-                b" BA" => Err(IMAPError::Bad(response.to_owned())),
-                _ => Err(IMAPError::Invalid(response.to_owned())),
+    /// Sends `APPEND`, with the message as a literal that is only sent once the server has
+    /// accepted the command so far.
+    fn append_message(imap: &mut Stream,
+                      tag: &mut Tag,
+                      mailbox: &str,
+                      flags: &[Flag],
+                      date: Option<DateTime>,
+                      message: &[u8])
+                      -> Result<Option<AppendUid>, IMAPError> {
+        let mut cmd = Command::new("APPEND").arg(utf7::encode(mailbox));
+        if !flags.is_empty() {
+            cmd = try!(cmd.flags(flags));
+        }
+        if let Some(date) = date {
+            cmd = cmd.raw(&format!("\"{}\"", date));
+        }
+        let cmd = cmd.literal(message);
+
+        let tag = tag.next_tag();
+        let res = try!(imap.command(&tag, &cmd));
+        let (_, completion) = try!(IMAPClient::parse_tagged_response(&res, &tag));
+        let completion = try!(IMAPClient::check_status(completion));
+
+        if let Some(ResponseCode::Other(ref name, Some(ref args))) = completion.code {
+            if name == "APPENDUID" {
+                let mut args = args.split(' ');
+                let uid_validity = args.next().and_then(|n| n.parse().ok());
+                let uid = args.next().and_then(|n| n.parse().ok());
+                if let (Some(uid_validity), Some(uid)) = (uid_validity, uid) {
+                    return Ok(Some(AppendUid {
+                        uid_validity: uid_validity,
+                        uid: uid,
+                    }));
+                }
             }
-        } else {
-            Err(IMAPError::Invalid(response.to_owned()))
+        }
+
+        Ok(None)
+    }
+
+    /// Sends `LIST` or `LSUB` and collects the mailboxes returned.
+    fn list_folders(imap: &mut Stream,
+                    tag: &mut Tag,
+                    command: &str,
+                    reference: &str,
+                    pattern: &str)
+                    -> Result<Vec<Folder>, IMAPError> {
+        let tag = tag.next_tag();
+        let cmd = Command::new(command)
+                      .arg(utf7::encode(reference))
+                      .arg(utf7::encode(pattern));
+        let res = try!(imap.command(&tag, &cmd));
+        let untagged = try!(IMAPClient::check_tagged_response(&res, &tag));
+
+        Ok(untagged.into_iter()
+                   .filter_map(|data| match data {
+                       UntaggedResponse::List(list) |
+                       UntaggedResponse::Lsub(list) => Some(Folder::from(list)),
+                       _ => None,
+                   })
+                   .collect())
+    }
+
+    /// Decodes the base64 encoded SASL challenge carried by a continuation request.
+    fn decode_challenge(continuation: &[u8]) -> Result<Vec<u8>, IMAPError> {
+        if let Response::Continuation(_, text) = try!(response::parse(continuation)) {
+            if let Some(challenge) = base64::decode(text.trim().as_bytes()) {
+                return Ok(challenge);
+            }
+        }
+
+        Err(IMAPError::Invalid(String::from_utf8_lossy(continuation).into_owned()))
+    }
+
+    /// Returns the capabilities cached on `imap`, sending `CAPABILITY` if there are none.
+    fn capability<'a>(imap: &'a mut Stream, tag: &mut Tag) -> Result<&'a Capabilities, IMAPError> {
+        if imap.capabilities().is_none() {
+            let tag = tag.next_tag();
+            let res = try!(imap.command(&tag, &Command::new("CAPABILITY")));
+            let (untagged, completion) = try!(IMAPClient::parse_tagged_response(&res, &tag));
+            let capabilities = IMAPClient::find_capabilities(&untagged, &completion);
+            try!(IMAPClient::check_status(completion));
+            imap.set_capabilities(Some(capabilities.unwrap_or(Capabilities::default())));
+        }
+
+        match imap.capabilities() {
+            Some(capabilities) => Ok(capabilities),
+            None => Err(IMAPError::Invalid("Server did not send its capabilities".to_owned())),
+        }
+    }
+
+    /// Checks the response to a command that authenticates the connection, returning the new
+    /// capabilities of the server if it included them.
+    ///
+    /// Capabilities may change once authenticated, so when the response does not include them the
+    /// old ones should no longer be trusted.
+    fn check_authenticated_response(response: &[Vec<u8>],
+                                    tag: &str)
+                                    -> Result<Option<Capabilities>, IMAPError> {
+        let (untagged, completion) = try!(IMAPClient::parse_tagged_response(response, tag));
+        let capabilities = IMAPClient::find_capabilities(&untagged, &completion);
+        try!(IMAPClient::check_status(completion));
+        Ok(capabilities)
+    }
+
+    /// Looks for capabilities in an untagged `CAPABILITY` response or a `CAPABILITY` response
+    /// code.
+    fn find_capabilities(untagged: &[UntaggedResponse],
+                         completion: &StatusResponse)
+                         -> Option<Capabilities> {
+        let codes = untagged.iter()
+                            .filter_map(|data| match *data {
+                                UntaggedResponse::Status(ref status) => status.code.as_ref(),
+                                _ => None,
+                            })
+                            .chain(completion.code.as_ref());
+
+        for code in codes {
+            if let &ResponseCode::Capability(ref names) = code {
+                return Some(names.iter().collect());
+            }
+        }
+
+        for data in untagged {
+            if let &UntaggedResponse::Capability(ref names) = data {
+                return Some(names.iter().collect());
+            }
+        }
+
+        None
+    }
+
+    fn parse_flags(flags: &[String]) -> Vec<Flag> {
+        flags.iter().map(|flag| Flag::from(&flag[..])).collect()
+    }
+
+    fn check_status(completion: StatusResponse) -> Result<StatusResponse, IMAPError> {
+        match completion.status {
+            Status::Ok => Ok(completion),
+            Status::No => {
+                Err(match completion.code {
+                    Some(ResponseCode::TryCreate) => IMAPError::TryCreate(completion.text),
+                    Some(ResponseCode::Other(ref name, _)) if name == "ALREADYEXISTS" => {
+                        IMAPError::AlreadyExists(completion.text)
+                    }
+                    Some(ResponseCode::Other(ref name, _)) if name == "NONEXISTENT" => {
+                        IMAPError::NonExistent(completion.text)
+                    }
+                    _ => IMAPError::No(completion.text),
+                })
+            }
+            Status::Bad => Err(IMAPError::Bad(completion.text)),
+            _ => Err(IMAPError::Invalid(completion.text)),
         }
     }
 }
 
 impl Mailbox {
+    fn new(server: MailServer, mailres: MailboxResponse, read_only: bool) -> Mailbox {
+        let read_only = read_only ||
+                        mailres.permission.as_ref().map_or(false, |p| p == "READ-ONLY");
+
+        Mailbox {
+            server: server,
+            flags: mailres.flags,
+            exists: mailres.exists,
+            recent: mailres.recent,
+            unseen: mailres.unseen,
+            permanentflags: mailres.permanentflags,
+            uidnext: mailres.uidnext,
+            uidvalidity: mailres.uidvalidity,
+            permission: mailres.permission,
+            read_only: read_only,
+        }
+    }
 
+    /// Whether the mailbox was opened with `examine`, or the server only granted read-only
+    /// access.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
 
+    /// Refuses `command` locally if the mailbox is read-only.
+    fn check_writable(&self, command: &str) -> Result<(), IMAPError> {
+        if self.read_only {
+            Err(IMAPError::ReadOnly(format!("Can not {} in a read-only mailbox", command)))
+        } else {
+            Ok(())
+        }
+    }
 
-    // fn CHECK() -> TypeName {
-    // unimplemented!()
-    // }
-    //
-    // fn CLOSE() -> TypeName {
-    // unimplemented!()
-    // }
-    //
-    // fn EXPUNGE() -> TypeName {
-    // unimplemented!()
-    // }
-    //
-    // fn SEARCH() -> TypeName {
-    // unimplemented!()
-    // }
-
-    pub fn fetch<T: Into<SequenceSet>, D: Into<DataItem>>(&mut self, sequence_set: T, data_item: D) -> Result<Vec<Email>, IMAPError> {
-        let sequence_set = sequence_set.into();
-        let data_item = data_item.into();
+    /// Asks the server to checkpoint the mailbox, e.g. by flushing it to disk.
+    pub fn check(&mut self) -> Result<(), IMAPError> {
+        let cmd = Command::new("CHECK");
+        IMAPClient::simple_command(&mut self.server.imap, &mut self.server.tag, &cmd)
+    }
 
-        let args = match sequence_set {
-            SequenceSet::Set(l, h) => format!("{}:{}", l.to_string(), h.to_string()),
-            _ => panic!(),
+    /// Permanently removes all messages flagged `\Deleted`. Returns the sequence number of each
+    /// message removed, in the order the server reported them. Each number is relative to the
+    /// mailbox as it was after the removals before it.
+    pub fn expunge(&mut self) -> Result<Vec<u32>, IMAPError> {
+        try!(self.check_writable("EXPUNGE"));
+        self.run_expunge(Command::new("EXPUNGE"))
+    }
 
-        };
+    /// Like `expunge`, but only removes messages whose UID is in `uid_set`, leaving any other
+    /// `\Deleted` messages alone. Needs a server that supports UIDPLUS (RFC 4315).
+    pub fn uid_expunge<T: Into<UidSet>>(&mut self, uid_set: T) -> Result<Vec<u32>, IMAPError> {
+        let uid_set = uid_set.into();
+        if uid_set.is_empty() {
+            return Err(IMAPError::Invalid("Can not EXPUNGE an empty UID set".to_owned()));
+        }
+        try!(self.check_writable("EXPUNGE"));
 
-        let data_item = match data_item {
-            DataItem::Macro(m)  => {
-                match m {
-                    Macro::All  => "ALL".to_owned(),
-                    Macro::Fast => "FAST".to_owned(),
-                    Macro::Full => "FULL".to_owned(),
-                }
-            },
-            DataItem::Raw(r)    => r
-        };
+        if !try!(self.capabilities()).has(&Capability::UidPlus) {
+            return Err(IMAPError::Unsupported("UID EXPUNGE".to_owned()));
+        }
+        self.run_expunge(Command::new("UID EXPUNGE").raw(&uid_set.to_string()))
+    }
 
-        let tag = self.tag.next_tag();
-        let cmd = format!("{} FETCH {} {}\r\n", tag, args, data_item);
+    fn run_expunge(&mut self, cmd: Command) -> Result<Vec<u32>, IMAPError> {
+        let tag = self.server.tag.next_tag();
+        let res = try!(self.server.command(&tag, &cmd));
+        let untagged = try!(IMAPClient::check_tagged_response(&res, &tag));
+
+        Ok(untagged.into_iter()
+                   .filter_map(|data| match data {
+                       UntaggedResponse::Expunge(n) => Some(n),
+                       _ => None,
+                   })
+                   .collect())
+    }
 
-        let response = try!(self.command(&cmd));
-        // println!("{}", response);
-        let response = try!(Mailbox::parse_fetch_response(&response));
-        Ok(response)
+    /// Returns the sequence numbers of the messages matching `query`.
+    pub fn search(&mut self, query: &SearchQuery) -> Result<SequenceSet, IMAPError> {
+        let found = try!(self.run_search("SEARCH", query));
+        Ok(found.into_iter().collect())
     }
 
-    fn parse_fetch_response<'a>(res: &'a str) -> Result<Vec<Email>, IMAPError> {
-        let emailre = Regex::new(r"\* \d+ FETCH(.*)\r\n").unwrap();
-        let mut emails = Vec::new();
-        let captures = emailre.captures_iter(res);
+    /// Like `search`, but returns the UIDs of the messages rather than their sequence numbers.
+    pub fn uid_search(&mut self, query: &SearchQuery) -> Result<UidSet, IMAPError> {
+        let found = try!(self.run_search("UID SEARCH", query));
+        Ok(UidSet::from_uids(found))
+    }
 
+    fn run_search(&mut self, command: &str, query: &SearchQuery) -> Result<Vec<u32>, IMAPError> {
+        let cmd = try!(Command::new(command).search(query));
+        let tag = self.server.tag.next_tag();
+        let res = try!(self.server.command(&tag, &cmd));
+        let untagged = try!(IMAPClient::check_tagged_response(&res, &tag));
 
-        for cap in captures {
-            if let Some(email) = cap.at(1) {
-                emails.push(email);
+        let mut ids = Vec::new();
+        for data in untagged {
+            if let UntaggedResponse::Search(found) = data {
+                ids.extend(found);
             }
         }
+        Ok(ids)
+    }
+
+    /// Changes the flags of the messages in `sequence_set`. Returns the flags of each changed
+    /// message, by sequence number, as the server reported them. With `silent` the server is asked
+    /// not to report them, so usually nothing is returned.
+    pub fn store<T: Into<SequenceSet>>(&mut self,
+                                       sequence_set: T,
+                                       mode: StoreMode,
+                                       flags: &[Flag],
+                                       silent: bool)
+                                       -> Result<Vec<(u32, Vec<Flag>)>, IMAPError> {
+        let sequence_set = sequence_set.into();
+        if sequence_set.is_empty() {
+            return Err(IMAPError::Invalid("Can not STORE to an empty sequence set".to_owned()));
+        }
+        self.run_store("STORE", &sequence_set.to_string(), mode, flags, silent)
+    }
+
+    /// Like `store`, but for the messages with the UIDs in `uid_set`. The flags returned are
+    /// keyed by UID rather than by sequence number.
+    pub fn uid_store<T: Into<UidSet>>(&mut self,
+                                      uid_set: T,
+                                      mode: StoreMode,
+                                      flags: &[Flag],
+                                      silent: bool)
+                                      -> Result<Vec<(u32, Vec<Flag>)>, IMAPError> {
+        let uid_set = uid_set.into();
+        if uid_set.is_empty() {
+            return Err(IMAPError::Invalid("Can not STORE to an empty UID set".to_owned()));
+        }
+        self.run_store("UID STORE", &uid_set.to_string(), mode, flags, silent)
+    }
+
+    fn run_store(&mut self,
+                 command: &str,
+                 set: &str,
+                 mode: StoreMode,
+                 flags: &[Flag],
+                 silent: bool)
+                 -> Result<Vec<(u32, Vec<Flag>)>, IMAPError> {
+        try!(self.check_writable("STORE"));
+
+        let by_uid = command.starts_with("UID");
+        let cmd = try!(Command::new(command)
+                           .raw(set)
+                           .raw(mode.item(silent))
+                           .flags(flags));
+        let tag = self.server.tag.next_tag();
+        let res = try!(self.server.command(&tag, &cmd));
+        let untagged = try!(IMAPClient::check_tagged_response(&res, &tag));
+
+        Ok(untagged.into_iter()
+                   .filter_map(|data| match data {
+                       UntaggedResponse::Fetch(seq, attributes) => {
+                           let mut id = if by_uid { None } else { Some(seq) };
+                           let mut flags = None;
+                           for (name, value) in attributes {
+                               match &name[..] {
+                                   "UID" if by_uid => id = value.as_number().map(|n| n as u32),
+                                   "FLAGS" => flags = Mailbox::parse_flag_list(&value),
+                                   _ => {}
+                               }
+                           }
+                           match (id, flags) {
+                               (Some(id), Some(flags)) => Some((id, flags)),
+                               _ => None,
+                           }
+                       }
+                       _ => None,
+                   })
+                   .collect())
+    }
 
-        if emails.is_empty() {
-            return Err(IMAPError::Invalid(res.to_owned()));
+    fn parse_flag_list(value: &Value) -> Option<Vec<Flag>> {
+        let list = try_opt!(value.as_list());
+        let mut flags = Vec::with_capacity(list.len());
+        for flag in list {
+            flags.push(Flag::from(&try_opt!(flag.as_string())[..]));
         }
+        Some(flags)
+    }
 
-        let emails = Mailbox::parse_emails(&emails);
-        Ok(emails)
+    /// The flags defined in the mailbox.
+    pub fn flags(&self) -> &[Flag] {
+        &self.flags
     }
 
-    fn parse_emails(emails: &[&str]) -> Vec<Email> {
-        let mut parsed_emails: Vec<Email> = Vec::with_capacity(emails.len());
-        for email in emails {
+    /// The flags that can be changed permanently, or `None` if the server did not say. If this
+    /// contains `Flag::MayCreate`, new keywords can be stored too.
+    pub fn permanent_flags(&self) -> Option<&[Flag]> {
+        self.permanentflags.as_ref().map(|flags| &flags[..])
+    }
 
-            let parsed_email = Email { full_text: (*email).to_owned() };
-            parsed_emails.push(parsed_email);
+    /// Copies the messages in `sequence_set` to the end of `mailbox`. If the server supports
+    /// UIDPLUS, returns the UIDs the copies were given.
+    pub fn copy<T: Into<SequenceSet>>(&mut self,
+                                      sequence_set: T,
+                                      mailbox: &str)
+                                      -> Result<Option<CopyUid>, IMAPError> {
+        let sequence_set = sequence_set.into();
+        if sequence_set.is_empty() {
+            return Err(IMAPError::Invalid("Can not COPY an empty sequence set".to_owned()));
         }
+        self.run_copy("COPY", &sequence_set.to_string(), mailbox)
+    }
 
-        parsed_emails
+    /// Like `copy`, but for the messages with the UIDs in `uid_set`.
+    pub fn uid_copy<T: Into<UidSet>>(&mut self,
+                                     uid_set: T,
+                                     mailbox: &str)
+                                     -> Result<Option<CopyUid>, IMAPError> {
+        let uid_set = uid_set.into();
+        if uid_set.is_empty() {
+            return Err(IMAPError::Invalid("Can not COPY an empty UID set".to_owned()));
+        }
+        self.run_copy("UID COPY", &uid_set.to_string(), mailbox)
     }
 
-    // fn STORE() -> TypeName {
-    // unimplemented!()
-    // }
-    //
-    // fn COPY() -> TypeName {
-    // unimplemented!()
-    // }
-    //
-    // fn UID() -> TypeName {
-    // unimplemented!()
-    // }
+    /// Moves the messages in `sequence_set` to the end of `mailbox`. If the server supports
+    /// UIDPLUS, returns the UIDs the moved messages were given.
+    ///
+    /// Uses `MOVE` (RFC 6851) where the server supports it. Otherwise the messages are copied,
+    /// flagged `\Deleted` and then expunged by UID, which needs UIDPLUS so that no other deleted
+    /// messages are expunged with them. Unlike `MOVE`, this fallback is not atomic.
+    pub fn mv<T: Into<SequenceSet>>(&mut self,
+                                    sequence_set: T,
+                                    mailbox: &str)
+                                    -> Result<Option<CopyUid>, IMAPError> {
+        let sequence_set = sequence_set.into();
+        if sequence_set.is_empty() {
+            return Err(IMAPError::Invalid("Can not MOVE an empty sequence set".to_owned()));
+        }
+        try!(self.check_writable("MOVE"));
 
+        let (native, uidplus) = {
+            let capabilities = try!(self.capabilities());
+            (capabilities.has(&Capability::Move), capabilities.has(&Capability::UidPlus))
+        };
 
-    fn command(&mut self, cmd: &str) -> Result<String, IMAPError> {
-        match &mut self.imap {
-            &mut IMAPConnection::Basic(ref mut stream) => {
-                let _ = stream.write(cmd.as_bytes());
-                let mut buf = String::new();
-                let _ = stream.read_to_string(&mut buf);
-                Ok(buf)
+        if native {
+            self.run_copy("MOVE", &sequence_set.to_string(), mailbox)
+        } else if uidplus {
+            // Messages expunged meanwhile are left out, and there may be none left to move
+            let uid_set = try!(self.fetch_uids(&sequence_set));
+            if uid_set.is_empty() {
+                return Ok(None);
             }
-            &mut IMAPConnection::Ssl(ref mut stream) => {
-                let _ = stream.write(cmd.as_bytes());
-                let mut buf = String::new();
-                let _ = stream.read_to_string(&mut buf);
-                Ok(buf)
+            self.move_by_copy(uid_set, mailbox)
+        } else {
+            Err(IMAPError::Unsupported("MOVE needs either MOVE or UIDPLUS".to_owned()))
+        }
+    }
+
+    /// Sends `COPY` or `MOVE`, or their UID forms, and looks for `COPYUID` in the responses.
+    /// It comes with the tagged completion of a copy, but in an untagged `OK` for a move.
+    fn run_copy(&mut self,
+                command: &str,
+                set: &str,
+                mailbox: &str)
+                -> Result<Option<CopyUid>, IMAPError> {
+        let tag = self.server.tag.next_tag();
+        let cmd = Command::new(command).raw(set).arg(utf7::encode(mailbox));
+        let res = try!(self.server.command(&tag, &cmd));
+        let (untagged, completion) = try!(IMAPClient::parse_tagged_response(&res, &tag));
+        let completion = try!(IMAPClient::check_status(completion));
+
+        let codes = untagged.into_iter()
+                            .filter_map(|data| match data {
+                                UntaggedResponse::Status(status) => status.code,
+                                _ => None,
+                            })
+                            .chain(completion.code);
+
+        for code in codes {
+            if let ResponseCode::Other(ref name, Some(ref args)) = code {
+                if name == "COPYUID" {
+                    return Ok(CopyUid::parse(args));
+                }
             }
-            &mut IMAPConnection::Disconnected =>
-                Err(IMAPError::LoginError("Not connected to server.".to_owned())),
         }
+
+        Ok(None)
+    }
+
+    /// Copies, deletes and expunges the messages with the UIDs in `uid_set`.
+    fn move_by_copy(&mut self, uid_set: UidSet, mailbox: &str) -> Result<Option<CopyUid>, IMAPError> {
+        let copyuid = try!(self.uid_copy(uid_set.clone(), mailbox));
+        try!(self.uid_store(uid_set.clone(), StoreMode::Add, &[Flag::Deleted], true));
+        try!(self.uid_expunge(uid_set));
+        Ok(copyuid)
+    }
+
+    /// Fetches the UIDs of the messages in `sequence_set`.
+    fn fetch_uids(&mut self, sequence_set: &SequenceSet) -> Result<UidSet, IMAPError> {
+        let tag = self.server.tag.next_tag();
+        let cmd = Command::new("FETCH").raw(&sequence_set.to_string()).raw("(UID)");
+        let res = try!(self.server.command(&tag, &cmd));
+        let untagged = try!(IMAPClient::check_tagged_response(&res, &tag));
+
+        Ok(UidSet(untagged.into_iter()
+                          .filter_map(|data| match data {
+                              UntaggedResponse::Fetch(_, attributes) => {
+                                  attributes.iter()
+                                            .find(|&&(ref name, _)| name == "UID")
+                                            .and_then(|&(_, ref value)| value.as_number())
+                                            .map(|uid| uid as u32)
+                              }
+                              _ => None,
+                          })
+                          .collect()))
     }
 }
 
 impl MailServer {
-    fn command(&mut self, cmd: &str) -> Result<String, IMAPError> {
-        match &mut self.imap {
-            &mut IMAPConnection::Basic(ref mut stream) => {
-                let _ = stream.write(cmd.as_bytes());
-                let mut buf = String::new();
-                let _ = stream.read_to_string(&mut buf);
-                Ok(buf)
-            }
-            &mut IMAPConnection::Ssl(ref mut stream) => {
-                let _ = stream.write(cmd.as_bytes());
-                let mut buf = String::new();
-                let _ = stream.read_to_string(&mut buf);
-                Ok(buf)
+    /// Returns the capabilities of the server, asking for them if none are cached.
+    pub fn capabilities(&mut self) -> Result<&Capabilities, IMAPError> {
+        IMAPClient::capability(&mut self.imap, &mut self.tag)
+    }
+
+    /// Lists the mailboxes matching `pattern`, which may contain the wildcards `*` and `%`,
+    /// relative to `reference`. Use `folder::tree` to arrange them into a hierarchy.
+    pub fn list(&mut self, reference: &str, pattern: &str) -> Result<Vec<Folder>, IMAPError> {
+        IMAPClient::list_folders(&mut self.imap, &mut self.tag, "LIST", reference, pattern)
+    }
+
+    /// Like `list`, but only returns subscribed mailboxes.
+    pub fn lsub(&mut self, reference: &str, pattern: &str) -> Result<Vec<Folder>, IMAPError> {
+        IMAPClient::list_folders(&mut self.imap, &mut self.tag, "LSUB", reference, pattern)
+    }
+
+    /// Asks for the status of `mailbox` without selecting it.
+    pub fn status(&mut self,
+                  mailbox: &str,
+                  items: &[StatusItem])
+                  -> Result<MailboxStatus, IMAPError> {
+        IMAPClient::mailbox_status(&mut self.imap, &mut self.tag, mailbox, items)
+    }
+
+    /// Appends `message`, a complete RFC 5322 message, to `mailbox` with the given flags. If
+    /// `date` is `None` the server uses the current time as the message's internal date.
+    /// Returns the UID the message was given, if the server supports UIDPLUS.
+    ///
+    /// Fails with `IMAPError::TryCreate` if `mailbox` does not exist but could be created.
+    pub fn append(&mut self,
+                  mailbox: &str,
+                  flags: &[Flag],
+                  date: Option<DateTime>,
+                  message: &[u8])
+                  -> Result<Option<AppendUid>, IMAPError> {
+        IMAPClient::append_message(&mut self.imap, &mut self.tag, mailbox, flags, date, message)
+    }
+
+    /// Creates `mailbox`. Fails with `IMAPError::AlreadyExists` if the server reports that it
+    /// already exists.
+    pub fn create(&mut self, mailbox: &str) -> Result<(), IMAPError> {
+        let cmd = Command::new("CREATE").arg(utf7::encode(mailbox));
+        IMAPClient::simple_command(&mut self.imap, &mut self.tag, &cmd)
+    }
+
+    /// Deletes `mailbox`. Fails with `IMAPError::NonExistent` if the server reports that there is
+    /// no such mailbox.
+    pub fn delete(&mut self, mailbox: &str) -> Result<(), IMAPError> {
+        let cmd = Command::new("DELETE").arg(utf7::encode(mailbox));
+        IMAPClient::simple_command(&mut self.imap, &mut self.tag, &cmd)
+    }
+
+    /// Renames `from` to `to`, along with any mailboxes below it in the hierarchy.
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<(), IMAPError> {
+        let cmd = Command::new("RENAME").arg(utf7::encode(from)).arg(utf7::encode(to));
+        IMAPClient::simple_command(&mut self.imap, &mut self.tag, &cmd)
+    }
+
+    /// Adds `mailbox` to the subscriptions returned by `lsub`.
+    pub fn subscribe(&mut self, mailbox: &str) -> Result<(), IMAPError> {
+        let cmd = Command::new("SUBSCRIBE").arg(utf7::encode(mailbox));
+        IMAPClient::simple_command(&mut self.imap, &mut self.tag, &cmd)
+    }
+
+    /// Removes `mailbox` from the subscriptions returned by `lsub`.
+    pub fn unsubscribe(&mut self, mailbox: &str) -> Result<(), IMAPError> {
+        let cmd = Command::new("UNSUBSCRIBE").arg(utf7::encode(mailbox));
+        IMAPClient::simple_command(&mut self.imap, &mut self.tag, &cmd)
+    }
+
+    fn command(&mut self, tag: &str, cmd: &Command) -> Result<Vec<Vec<u8>>, IMAPError> {
+        self.imap.command(tag, cmd)
+    }
+}
+
+impl Deref for Mailbox {
+    type Target = MailServer;
+
+    fn deref(&self) -> &MailServer {
+        &self.server
+    }
+}
+
+impl DerefMut for Mailbox {
+    fn deref_mut(&mut self) -> &mut MailServer {
+        &mut self.server
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use openssl::crypto::hash;
+    use openssl::ssl::{SslContext, SslMethod, SslStream};
+    use openssl::x509::X509Generator;
+    use date::{Date, DateTime};
+    use flag::Flag;
+    use folder;
+    use imaperror::IMAPError;
+    use super::{AppendUid, Capability, CopyUid, IMAPClient, IMAPConnection, MailServer,
+                MailboxStatus, SequenceSet, StatusItem, StoreMode, UidSet};
+
+    #[test]
+    fn sequence_sets_are_compressed() {
+        let set: SequenceSet = vec![9, 1, 3, 2, 7, 10, 3].into();
+        assert_eq!(set.to_string(), "1:3,7,9:10");
+        assert_eq!(set.numbers(), [1, 2, 3, 7, 9, 10]);
+
+        assert_eq!(SequenceSet::from(vec![4]), SequenceSet::Atom(4));
+        assert_eq!(SequenceSet::from(vec![4, 5]), SequenceSet::Set(4, 5));
+        assert_eq!(SequenceSet::from(&[1, 3][..]).to_string(), "1,3");
+        assert_eq!((1..4).chain(6..8).collect::<SequenceSet>().to_string(), "1:3,6:7");
+    }
+
+    #[test]
+    fn empty_sequence_sets() {
+        let set: SequenceSet = vec![].into();
+        assert!(set.is_empty());
+        assert_eq!(set.numbers(), []);
+        assert!(UidSet::from_uids(vec![]).is_empty());
+
+        assert!(!SequenceSet::from(1).is_empty());
+        assert!(!SequenceSet::Last.is_empty());
+    }
+
+    #[test]
+    fn sequence_set_forms() {
+        assert_eq!(SequenceSet::from((2, 5)).to_string(), "2:5");
+        assert_eq!(SequenceSet::from(3..).to_string(), "3:*");
+        assert_eq!(SequenceSet::Last.to_string(), "*");
+        assert_eq!(SequenceSet::from((5, 2)).numbers(), [5, 4, 3, 2]);
+        assert_eq!(SequenceSet::List(vec![SequenceSet::Atom(1), SequenceSet::Open(5)]).numbers(),
+                   [1]);
+        assert_eq!(SequenceSet::from(vec![u32::max_value(), 1, u32::max_value() - 1]).to_string(),
+                   "1,4294967294:4294967295");
+    }
+
+    #[test]
+    fn uid_sets() {
+        let set = UidSet::from_uids(vec![102, 100, 101, 200]);
+        assert_eq!(set.to_string(), "100:102,200");
+        assert_eq!(set.uids(), [100, 101, 102, 200]);
+        assert_eq!(UidSet::single(7).to_string(), "7");
+        assert_eq!(UidSet::range(7, 9).to_string(), "7:9");
+        assert_eq!(UidSet::range_from(7).to_string(), "7:*");
+    }
+
+    #[test]
+    fn copy_uid() {
+        // The example of section 3 of RFC 4315
+        let copy_uid = CopyUid::parse("38505 304,319:320 3956:3958").unwrap();
+        assert_eq!(copy_uid.uid_validity, 38505);
+        assert_eq!(copy_uid.uids.len(), 3);
+        assert_eq!(copy_uid.uids[&304], 3956);
+        assert_eq!(copy_uid.uids[&319], 3957);
+        assert_eq!(copy_uid.uids[&320], 3958);
+
+        // 5:3 is the same range as 3:5 (section 4 of RFC 4315), and so is 12:10
+        let copy_uid = CopyUid::parse("1 5:3 12:10").unwrap();
+        assert_eq!(copy_uid.uids[&3], 10);
+        assert_eq!(copy_uid.uids[&4], 11);
+        assert_eq!(copy_uid.uids[&5], 12);
+    }
+
+    #[test]
+    fn invalid_copy_uid() {
+        assert_eq!(CopyUid::parse("38505 304,319:320 3956:3957"), None);
+        assert_eq!(CopyUid::parse("38505 304"), None);
+        assert_eq!(CopyUid::parse("abc 1 2"), None);
+        assert_eq!(CopyUid::parse("1 1:x 2"), None);
+    }
+
+    #[test]
+    fn mailbox_runs_server_commands() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            writer.write_all(b"* PREAUTH ready\r\n").unwrap();
+
+            let mut commands = Vec::new();
+            for response in &["* 0 EXISTS\r\n* 0 RECENT\r\n* FLAGS ()\r\n", ""] {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let (tag, command) = line.split_at(line.find(' ').unwrap());
+                writer.write_all(format!("{}{} OK done\r\n", response, tag).as_bytes()).unwrap();
+                commands.push(command.trim().to_owned());
             }
-            &mut IMAPConnection::Disconnected =>
-                Err(IMAPError::LoginError("Not connected to server.".to_owned())),
+            commands
+        });
+
+        let imap = IMAPClient::connect(IMAPConnection::Basic(TcpStream::connect(addr).unwrap()));
+        match imap.unwrap().select("INBOX") {
+            Ok(IMAPClient::Selected(ref mut mailbox)) => mailbox.create("Archive").unwrap(),
+            _ => panic!("SELECT failed"),
         }
+        assert_eq!(server.join().unwrap(), ["SELECT INBOX", "CREATE Archive"]);
+    }
+
+    #[test]
+    fn untrusted_certificates_are_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (cert, key) = X509Generator::new()
+                                  .set_bitlength(2048)
+                                  .set_valid_period(1)
+                                  .add_name("CN".to_owned(), "localhost".to_owned())
+                                  .set_sign_hash(hash::Type::SHA256)
+                                  .generate()
+                                  .unwrap();
+            let mut context = SslContext::new(SslMethod::Sslv23).unwrap();
+            context.set_certificate(&cert).unwrap();
+            context.set_private_key(&key).unwrap();
+
+            let (stream, _) = listener.accept().unwrap();
+            // The handshake fails as soon as the client rejects the self-signed certificate
+            let _ = SslStream::accept(&context, stream);
+        });
+
+        let stream = TcpStream::connect(addr).unwrap();
+        assert!(IMAPConnection::wrap_tls(stream, "localhost").is_err());
+        server.join().unwrap();
+    }
+
+    /// Connects to a server that sends the first line of `script` as its greeting, then answers
+    /// each line it receives with the next, in which `TAG` stands for the tag of the command.
+    /// Joining the returned thread gives the lines received, without their tags.
+    fn scripted_server(script: &'static [&'static str])
+                       -> (IMAPClient, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            writer.write_all(script[0].as_bytes()).unwrap();
+
+            let mut lines = Vec::new();
+            let mut tag = String::new();
+            let mut continued = false;
+            for response in &script[1..] {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                // A line sent after a continuation request carries on the same command
+                if continued {
+                    lines.push(line.trim().to_owned());
+                } else {
+                    let (command_tag, command) = line.split_at(line.find(' ').unwrap());
+                    tag = command_tag.to_owned();
+                    lines.push(command.trim().to_owned());
+                }
+                continued = response.starts_with('+');
+                writer.write_all(response.replace("TAG", &tag).as_bytes()).unwrap();
+            }
+            lines
+        });
+
+        let stream = TcpStream::connect(addr).unwrap();
+        (IMAPClient::connect(IMAPConnection::Basic(stream)).unwrap(), server)
+    }
+
+    fn authenticated(client: IMAPClient) -> MailServer {
+        match client {
+            IMAPClient::Authenticated(server) => server,
+            _ => panic!("Not authenticated"),
+        }
+    }
+
+    #[test]
+    fn status() {
+        let (client, server) =
+            scripted_server(&["* PREAUTH [CAPABILITY IMAP4rev1] ready\r\n",
+                              "* STATUS blurdybloop (MESSAGES 231 UIDNEXT 44292 \
+                               UIDVALIDITY 3857529045)\r\nTAG OK STATUS completed\r\n"]);
+        let items = [StatusItem::Messages, StatusItem::UidNext, StatusItem::UidValidity];
+        let status = authenticated(client).status("blurdybloop", &items).unwrap();
+        assert_eq!(status,
+                   MailboxStatus {
+                       messages: Some(231),
+                       uid_next: Some(44292),
+                       uid_validity: Some(3857529045),
+                       ..MailboxStatus::default()
+                   });
+        assert_eq!(server.join().unwrap(),
+                   ["STATUS blurdybloop (MESSAGES UIDNEXT UIDVALIDITY)"]);
+    }
+
+    #[test]
+    fn status_values_out_of_range() {
+        let (client, server) = scripted_server(&["* PREAUTH [CAPABILITY IMAP4rev1] ready\r\n",
+                                                 "* STATUS INBOX (UIDNEXT 4294967296)\r\n\
+                                                  TAG OK STATUS completed\r\n"]);
+        assert!(authenticated(client).status("INBOX", &[StatusItem::UidNext]).is_err());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn capabilities() {
+        let (client, server) =
+            scripted_server(&["* PREAUTH ready\r\n",
+                              "* CAPABILITY IMAP4rev1 STARTTLS AUTH=PLAIN auth=xoauth2 X-Custom\r\n\
+                               TAG OK done\r\n"]);
+        let mut imap = authenticated(client);
+        {
+            let capabilities = imap.capabilities().unwrap();
+            assert!(capabilities.has(&Capability::Imap4rev1));
+            assert!(capabilities.has(&Capability::StartTls));
+            assert!(!capabilities.has(&Capability::Idle));
+            assert!(capabilities.has_auth("plain"));
+            assert!(capabilities.has_auth("XOAUTH2"));
+            assert!(capabilities.has_str("x-custom"));
+            let mut mechanisms = capabilities.auth_mechanisms();
+            mechanisms.sort();
+            assert_eq!(mechanisms, ["PLAIN", "XOAUTH2"]);
+        }
+
+        // Asking again is answered from the cache
+        imap.capabilities().unwrap();
+        assert_eq!(server.join().unwrap(), ["CAPABILITY"]);
+    }
+
+    #[test]
+    fn folder_tree() {
+        let (client, server) = scripted_server(&["* PREAUTH ready\r\n",
+                                                 "* LIST (\\HasChildren) \"/\" INBOX\r\n\
+                                                  * LIST () \"/\" INBOX/Work\r\n\
+                                                  * LIST (\\Noselect) \"/\" Archive\r\n\
+                                                  * LIST () \"/\" Archive/2016/May\r\n\
+                                                  * LIST () \"/\" Archive/2016\r\n\
+                                                  * LIST () \"/\" Entw&APw-rfe\r\n\
+                                                  * LIST () \"/\" Projects/Alpha\r\n\
+                                                  TAG OK done\r\n"]);
+        let folders = authenticated(client).list("", "*").unwrap();
+        assert_eq!(folders.len(), 7);
+        assert_eq!(folders[3].path(), ["Archive", "2016", "May"]);
+
+        let tree = folder::tree(folders);
+        let names: Vec<&str> = tree.iter().map(|node| node.name()).collect();
+        assert_eq!(names, ["INBOX", "Archive", "Entw\u{fc}rfe", "Projects"]);
+
+        assert_eq!(tree[0].children().len(), 1);
+        assert_eq!(tree[0].children()[0].folder().unwrap().name(), "INBOX/Work");
+        assert!(!tree[1].folder().unwrap().is_selectable());
+        // Archive/2016 is listed after its child, but still takes its place above it
+        let year = &tree[1].children()[0];
+        assert_eq!(year.folder().unwrap().name(), "Archive/2016");
+        assert_eq!(year.children()[0].folder().unwrap().name(), "Archive/2016/May");
+        // Projects was not listed itself
+        assert_eq!(tree[3].folder(), None);
+        assert_eq!(tree[3].children()[0].name(), "Alpha");
+
+        assert_eq!(server.join().unwrap(), ["LIST \"\" \"*\""]);
+    }
+
+    #[test]
+    fn examined_mailboxes_are_read_only() {
+        let (client, server) =
+            scripted_server(&["* PREAUTH [CAPABILITY IMAP4rev1 UIDPLUS] ready\r\n",
+                              "* 3 EXISTS\r\n* 0 RECENT\r\n* FLAGS (\\Seen \\Deleted)\r\n\
+                               TAG OK [READ-ONLY] done\r\n"]);
+        let mut mailbox = match client.examine("INBOX") {
+            Ok(IMAPClient::Selected(mailbox)) => mailbox,
+            _ => panic!("EXAMINE failed"),
+        };
+        assert!(mailbox.is_read_only());
+
+        // None of these reach the server
+        match mailbox.store(1, StoreMode::Add, &[Flag::Deleted], true) {
+            Err(IMAPError::ReadOnly(_)) => {}
+            result => panic!("{:?}", result),
+        }
+        match mailbox.expunge() {
+            Err(IMAPError::ReadOnly(_)) => {}
+            result => panic!("{:?}", result),
+        }
+        match mailbox.uid_expunge(UidSet::single(7)) {
+            Err(IMAPError::ReadOnly(_)) => {}
+            result => panic!("{:?}", result),
+        }
+        assert_eq!(server.join().unwrap(), ["EXAMINE INBOX"]);
+    }
+
+    #[test]
+    fn expunge_and_close() {
+        let (client, server) =
+            scripted_server(&["* PREAUTH [CAPABILITY IMAP4rev1] ready\r\n",
+                              "* 4 EXISTS\r\n* 0 RECENT\r\n* FLAGS (\\Seen \\Deleted)\r\n\
+                               TAG OK [READ-WRITE] done\r\n",
+                              "* 3 EXPUNGE\r\n* 3 EXPUNGE\r\nTAG OK done\r\n",
+                              "TAG OK done\r\n"]);
+        let client = client.select("INBOX").map_err(|e| e.1).unwrap();
+
+        // Without the capability, UNSELECT is refused before it is sent
+        let mut client = match client.unselect() {
+            Err((client @ IMAPClient::Selected(_), IMAPError::Unsupported(_))) => client,
+            _ => panic!("UNSELECT was not refused"),
+        };
+        if let IMAPClient::Selected(ref mut mailbox) = client {
+            assert_eq!(mailbox.expunge().unwrap(), [3, 3]);
+        }
+        match client.close() {
+            Ok(IMAPClient::Authenticated(_)) => {}
+            _ => panic!("CLOSE failed"),
+        }
+        assert_eq!(server.join().unwrap(), ["SELECT INBOX", "EXPUNGE", "CLOSE"]);
+    }
+
+    #[test]
+    fn unselect() {
+        let (client, server) =
+            scripted_server(&["* PREAUTH [CAPABILITY IMAP4rev1 UNSELECT] ready\r\n",
+                              "* 4 EXISTS\r\n* 0 RECENT\r\n* FLAGS ()\r\nTAG OK done\r\n",
+                              "TAG OK done\r\n"]);
+        let client = client.select("INBOX").map_err(|e| e.1).unwrap();
+        match client.unselect() {
+            Ok(IMAPClient::Authenticated(_)) => {}
+            _ => panic!("UNSELECT failed"),
+        }
+        assert_eq!(server.join().unwrap(), ["SELECT INBOX", "UNSELECT"]);
     }
-}
 
-// #[cfg(test)]
-// mod tests {
-//     use super::*;
-//
-// }
+    #[test]
+    fn append() {
+        let (client, server) =
+            scripted_server(&["* PREAUTH [CAPABILITY IMAP4rev1 UIDPLUS] ready\r\n",
+                              "+ Ready for literal data\r\n",
+                              "TAG OK [APPENDUID 38505 3955] APPEND completed\r\n",
+                              "+ Ready for literal data\r\n",
+                              "TAG OK APPEND completed\r\n"]);
+        let mut imap = authenticated(client);
+        let date = DateTime::new(Date::new(2016, 5, 1).unwrap(), 9, 30, 0, 120).unwrap();
+        assert_eq!(imap.append("Drafts", &[Flag::Seen, Flag::Draft], Some(date), b"Hello")
+                       .unwrap(),
+                   Some(AppendUid {
+                       uid_validity: 38505,
+                       uid: 3955,
+                   }));
+        assert_eq!(imap.append("INBOX", &[], None, b"Hello").unwrap(), None);
+
+        assert_eq!(server.join().unwrap(),
+                   ["APPEND Drafts (\\Seen \\Draft) \"01-May-2016 09:30:00 +0200\" {5}",
+                    "Hello",
+                    "APPEND INBOX {5}",
+                    "Hello"]);
+    }
+}
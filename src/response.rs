@@ -0,0 +1,791 @@
+//! Typed representation of server responses, as described in section 7 of RFC 3501.
+//!
+//! Each complete response read from the server (one line, plus the contents of any literals it
+//! announced) is turned into a `Response` by `parse`.
+
+use imaperror::IMAPError;
+
+/// A single piece of data sent by the server.
+///
+/// Quoted strings and literals are both represented as `Value::String`, since the two are
+/// interchangeable on the wire.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Atom(String),
+    Number(u64),
+    String(Vec<u8>),
+    Nil,
+    List(Vec<Value>),
+}
+
+/// The status of a tagged or untagged status response.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    Ok,
+    No,
+    Bad,
+    PreAuth,
+    Bye,
+}
+
+/// The bracketed response code that may precede the human readable text of a status response.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ResponseCode {
+    Alert,
+    BadCharset(Vec<String>),
+    Capability(Vec<String>),
+    Parse,
+    PermanentFlags(Vec<String>),
+    ReadOnly,
+    ReadWrite,
+    TryCreate,
+    UidNext(u32),
+    UidValidity(u32),
+    Unseen(u32),
+    /// Any other response code, with the raw text of its arguments.
+    Other(String, Option<String>),
+}
+
+/// A status response: `OK`, `NO`, `BAD`, `PREAUTH` or `BYE`, with its optional response code
+/// and text.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatusResponse {
+    pub status: Status,
+    pub code: Option<ResponseCode>,
+    pub text: String,
+}
+
+/// A mailbox returned by `LIST` or `LSUB`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MailboxList {
+    pub attributes: Vec<String>,
+    pub delimiter: Option<String>,
+    pub name: String,
+}
+
+/// Data sent by the server in an untagged (`*`) response.
+#[derive(Debug, Clone, PartialEq)]
+pub enum UntaggedResponse {
+    Status(StatusResponse),
+    Capability(Vec<String>),
+    Flags(Vec<String>),
+    List(MailboxList),
+    Lsub(MailboxList),
+    Search(Vec<u32>),
+    /// The mailbox name and the item/value pairs of a `STATUS` response.
+    MailboxStatus(String, Vec<(String, u64)>),
+    Exists(u32),
+    Recent(u32),
+    Expunge(u32),
+    /// The message sequence number and the (upper-cased) name/value pairs of a `FETCH` response.
+    Fetch(u32, Vec<(String, Value)>),
+    /// Any other untagged response, with its name and the values that followed it.
+    Other(String, Vec<Value>),
+}
+
+/// A complete server response.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Response {
+    /// The completion result of the command with the given tag.
+    Tagged(String, StatusResponse),
+    Untagged(UntaggedResponse),
+    /// A `+` continuation request, with its optional response code and text.
+    Continuation(Option<ResponseCode>, String),
+}
+
+impl Value {
+    /// Returns the value as a string if it is an atom, a number or a string.
+    pub fn as_string(&self) -> Option<String> {
+        match *self {
+            Value::Atom(ref atom) => Some(atom.to_owned()),
+            Value::Number(number) => Some(number.to_string()),
+            Value::String(ref string) => Some(String::from_utf8_lossy(string).into_owned()),
+            _ => None,
+        }
+    }
+
+    /// Returns the raw bytes of a string, or `None` for any other value.
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match *self {
+            Value::String(ref string) => Some(string),
+            _ => None,
+        }
+    }
+
+    pub fn as_number(&self) -> Option<u64> {
+        match *self {
+            Value::Number(number) => Some(number),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&[Value]> {
+        match *self {
+            Value::List(ref list) => Some(list),
+            _ => None,
+        }
+    }
+
+    pub fn is_nil(&self) -> bool {
+        *self == Value::Nil
+    }
+}
+
+/// Parses one complete response, as read from the server.
+pub fn parse(response: &[u8]) -> Result<Response, IMAPError> {
+    let mut parser = Parser::new(response);
+    match parser.response() {
+        Some(response) => Ok(response),
+        None => Err(IMAPError::Invalid(String::from_utf8_lossy(response).into_owned())),
+    }
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(input: &'a [u8]) -> Parser<'a> {
+        Parser {
+            input: input,
+            pos: 0,
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).cloned()
+    }
+
+    fn eat(&mut self, byte: u8) -> bool {
+        if self.peek() == Some(byte) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, byte: u8) -> Option<()> {
+        if self.eat(byte) {
+            Some(())
+        } else {
+            None
+        }
+    }
+
+    fn at_end(&self) -> bool {
+        match self.peek() {
+            None | Some(b'\r') | Some(b'\n') => true,
+            _ => false,
+        }
+    }
+
+    fn response(&mut self) -> Option<Response> {
+        if self.eat(b'+') {
+            self.eat(b' ');
+            let (code, text) = try_opt!(self.resp_text());
+            return Some(Response::Continuation(code, text));
+        }
+
+        if self.eat(b'*') {
+            try_opt!(self.expect(b' '));
+            return self.untagged().map(Response::Untagged);
+        }
+
+        let tag = try_opt!(self.atom());
+        try_opt!(self.expect(b' '));
+        let status = try_opt!(self.status());
+        Some(Response::Tagged(tag, status))
+    }
+
+    fn untagged(&mut self) -> Option<UntaggedResponse> {
+        let name = try_opt!(self.atom());
+
+        if let Ok(number) = name.parse::<u32>() {
+            try_opt!(self.expect(b' '));
+            let name = try_opt!(self.atom()).to_uppercase();
+            return match &name[..] {
+                "EXISTS" => Some(UntaggedResponse::Exists(number)),
+                "RECENT" => Some(UntaggedResponse::Recent(number)),
+                "EXPUNGE" => Some(UntaggedResponse::Expunge(number)),
+                "FETCH" => {
+                    try_opt!(self.expect(b' '));
+                    let attributes = try_opt!(self.fetch_attributes());
+                    Some(UntaggedResponse::Fetch(number, attributes))
+                }
+                _ => {
+                    let mut values = vec![Value::Number(number as u64)];
+                    values.extend(try_opt!(self.values()));
+                    Some(UntaggedResponse::Other(name, values))
+                }
+            };
+        }
+
+        let name = name.to_uppercase();
+        match &name[..] {
+            "OK" | "NO" | "BAD" | "PREAUTH" | "BYE" => {
+                self.pos -= name.len();
+                self.status().map(UntaggedResponse::Status)
+            }
+            "CAPABILITY" => self.atoms().map(UntaggedResponse::Capability),
+            "FLAGS" => {
+                try_opt!(self.expect(b' '));
+                self.flag_list().map(UntaggedResponse::Flags)
+            }
+            "LIST" => self.mailbox_list().map(UntaggedResponse::List),
+            "LSUB" => self.mailbox_list().map(UntaggedResponse::Lsub),
+            "SEARCH" => {
+                let mut ids = Vec::new();
+                for value in try_opt!(self.values()) {
+                    if let Value::Number(id) = value {
+                        ids.push(id as u32);
+                    }
+                }
+                Some(UntaggedResponse::Search(ids))
+            }
+            "STATUS" => {
+                try_opt!(self.expect(b' '));
+                let mailbox = try_opt!(try_opt!(self.astring()).as_string());
+                self.eat(b' ');
+                let items = try_opt!(self.list());
+                let mut pairs = Vec::new();
+                for pair in items.chunks(2) {
+                    if pair.len() == 2 {
+                        let name = try_opt!(pair[0].as_string()).to_uppercase();
+                        pairs.push((name, try_opt!(pair[1].as_number())));
+                    }
+                }
+                Some(UntaggedResponse::MailboxStatus(mailbox, pairs))
+            }
+            _ => self.values().map(|values| UntaggedResponse::Other(name, values)),
+        }
+    }
+
+    /// `status SP resp-text`
+    fn status(&mut self) -> Option<StatusResponse> {
+        let status = match &try_opt!(self.atom()).to_uppercase()[..] {
+            "OK" => Status::Ok,
+            "NO" => Status::No,
+            "BAD" => Status::Bad,
+            "PREAUTH" => Status::PreAuth,
+            "BYE" => Status::Bye,
+            _ => return None,
+        };
+        self.eat(b' ');
+        let (code, text) = try_opt!(self.resp_text());
+
+        Some(StatusResponse {
+            status: status,
+            code: code,
+            text: text,
+        })
+    }
+
+    /// `["[" resp-text-code "]" SP] text`
+    fn resp_text(&mut self) -> Option<(Option<ResponseCode>, String)> {
+        let mut code = None;
+        if self.eat(b'[') {
+            code = Some(try_opt!(self.resp_text_code()));
+            try_opt!(self.expect(b']'));
+            self.eat(b' ');
+        }
+
+        Some((code, self.text()))
+    }
+
+    fn resp_text_code(&mut self) -> Option<ResponseCode> {
+        let name = try_opt!(self.atom()).to_uppercase();
+        let code = match &name[..] {
+            "ALERT" => ResponseCode::Alert,
+            "PARSE" => ResponseCode::Parse,
+            "READ-ONLY" => ResponseCode::ReadOnly,
+            "READ-WRITE" => ResponseCode::ReadWrite,
+            "TRYCREATE" => ResponseCode::TryCreate,
+            "UIDNEXT" => ResponseCode::UidNext(try_opt!(self.code_number())),
+            "UIDVALIDITY" => ResponseCode::UidValidity(try_opt!(self.code_number())),
+            "UNSEEN" => ResponseCode::Unseen(try_opt!(self.code_number())),
+            "CAPABILITY" => ResponseCode::Capability(try_opt!(self.atoms())),
+            "PERMANENTFLAGS" => {
+                try_opt!(self.expect(b' '));
+                ResponseCode::PermanentFlags(try_opt!(self.flag_list()))
+            }
+            "BADCHARSET" => {
+                let mut charsets = Vec::new();
+                if self.eat(b' ') {
+                    for charset in try_opt!(self.list()) {
+                        charsets.push(try_opt!(charset.as_string()));
+                    }
+                }
+                ResponseCode::BadCharset(charsets)
+            }
+            _ => {
+                let mut args = None;
+                if self.eat(b' ') {
+                    let start = self.pos;
+                    while self.peek().map_or(false, |b| b != b']' && b != b'\r' && b != b'\n') {
+                        self.pos += 1;
+                    }
+                    args = Some(String::from_utf8_lossy(&self.input[start..self.pos])
+                                    .into_owned());
+                }
+                ResponseCode::Other(name, args)
+            }
+        };
+
+        Some(code)
+    }
+
+    fn code_number(&mut self) -> Option<u32> {
+        try_opt!(self.expect(b' '));
+        try_opt!(self.atom()).parse().ok()
+    }
+
+    /// Everything up to the end of the line.
+    fn text(&mut self) -> String {
+        let start = self.pos;
+        while !self.at_end() {
+            self.pos += 1;
+        }
+        String::from_utf8_lossy(&self.input[start..self.pos]).into_owned()
+    }
+
+    /// A sequence of atoms separated by spaces, running to the end of the line or response code.
+    fn atoms(&mut self) -> Option<Vec<String>> {
+        let mut atoms = Vec::new();
+        while self.eat(b' ') {
+            if self.at_end() || self.peek() == Some(b']') {
+                break;
+            }
+            atoms.push(try_opt!(self.atom()));
+        }
+        Some(atoms)
+    }
+
+    /// A parenthesized list of flags or mailbox attributes.
+    fn flag_list(&mut self) -> Option<Vec<String>> {
+        let mut flags = Vec::new();
+        for flag in try_opt!(self.list()) {
+            flags.push(try_opt!(flag.as_string()));
+        }
+        Some(flags)
+    }
+
+    /// `"(" mbx-list-flags ")" SP (DQUOTE QUOTED-CHAR DQUOTE / nil) SP mailbox`
+    fn mailbox_list(&mut self) -> Option<MailboxList> {
+        try_opt!(self.expect(b' '));
+        let attributes = try_opt!(self.flag_list());
+        try_opt!(self.expect(b' '));
+        let delimiter = try_opt!(self.value()).as_string();
+        try_opt!(self.expect(b' '));
+        let name = try_opt!(try_opt!(self.astring()).as_string());
+
+        Some(MailboxList {
+            attributes: attributes,
+            delimiter: delimiter,
+            name: name,
+        })
+    }
+
+    /// The name/value pairs of a `FETCH` response.
+    fn fetch_attributes(&mut self) -> Option<Vec<(String, Value)>> {
+        let values = try_opt!(self.list());
+        let mut attributes = Vec::new();
+        let mut values = values.into_iter();
+
+        while let Some(name) = values.next() {
+            let name = try_opt!(name.as_string()).to_uppercase();
+            let value = try_opt!(values.next());
+            attributes.push((name, value));
+        }
+
+        Some(attributes)
+    }
+
+    /// Space separated values running to the end of the line.
+    fn values(&mut self) -> Option<Vec<Value>> {
+        let mut values = Vec::new();
+        while self.eat(b' ') {
+            if self.at_end() {
+                break;
+            }
+            values.push(try_opt!(self.value()));
+        }
+        Some(values)
+    }
+
+    fn list(&mut self) -> Option<Vec<Value>> {
+        try_opt!(self.expect(b'('));
+        let mut values = Vec::new();
+
+        loop {
+            if self.eat(b')') {
+                return Some(values);
+            }
+            // Address lists and multipart bodies are sent without spaces between their elements
+            if !values.is_empty() && !self.eat(b' ') && self.peek() != Some(b'(') {
+                return None;
+            }
+            values.push(try_opt!(self.value()));
+        }
+    }
+
+    fn value(&mut self) -> Option<Value> {
+        match try_opt!(self.peek()) {
+            b'(' => self.list().map(Value::List),
+            b'"' => self.quoted().map(Value::String),
+            b'{' => self.literal().map(Value::String),
+            _ => {
+                let atom = try_opt!(self.atom());
+                if atom.to_uppercase() == "NIL" {
+                    Some(Value::Nil)
+                } else if let Ok(number) = atom.parse() {
+                    Some(Value::Number(number))
+                } else {
+                    Some(Value::Atom(atom))
+                }
+            }
+        }
+    }
+
+    /// An `astring`, such as a mailbox name. Unlike an atom, it may contain `]`.
+    fn astring(&mut self) -> Option<Value> {
+        match try_opt!(self.peek()) {
+            b'"' | b'{' => self.value(),
+            _ => {
+                let start = self.pos;
+                while let Some(byte) = self.peek() {
+                    match byte {
+                        b' ' | b'(' | b')' | b'{' | b'"' | b'\r' | b'\n' => break,
+                        _ => self.pos += 1,
+                    }
+                }
+
+                if self.pos == start {
+                    return None;
+                }
+                let astring = String::from_utf8_lossy(&self.input[start..self.pos]);
+                Some(Value::Atom(astring.into_owned()))
+            }
+        }
+    }
+
+    fn quoted(&mut self) -> Option<Vec<u8>> {
+        try_opt!(self.expect(b'"'));
+        let mut string = Vec::new();
+
+        loop {
+            match try_opt!(self.peek()) {
+                b'"' => {
+                    self.pos += 1;
+                    return Some(string);
+                }
+                b'\\' => {
+                    self.pos += 1;
+                    string.push(try_opt!(self.peek()));
+                }
+                b'\r' | b'\n' => return None,
+                byte => string.push(byte),
+            }
+            self.pos += 1;
+        }
+    }
+
+    /// `"{" number "}" CRLF *CHAR8`
+    fn literal(&mut self) -> Option<Vec<u8>> {
+        try_opt!(self.expect(b'{'));
+        let start = self.pos;
+        while self.peek().map_or(false, |b| b >= b'0' && b <= b'9') {
+            self.pos += 1;
+        }
+        let len: usize = try_opt!(String::from_utf8_lossy(&self.input[start..self.pos])
+                                      .parse()
+                                      .ok());
+        try_opt!(self.expect(b'}'));
+        try_opt!(self.expect(b'\r'));
+        try_opt!(self.expect(b'\n'));
+
+        if len > self.input.len() - self.pos {
+            return None;
+        }
+        let literal = self.input[self.pos..self.pos + len].to_vec();
+        self.pos += len;
+        Some(literal)
+    }
+
+    /// An atom. Atoms here are read liberally: flags such as `\Seen` and `\*` are included, as
+    /// are the bracketed section and partial range of fetch attributes like
+    /// `BODY[HEADER.FIELDS (FROM)]<0>`.
+    fn atom(&mut self) -> Option<String> {
+        let start = self.pos;
+
+        while let Some(byte) = self.peek() {
+            match byte {
+                b' ' | b'(' | b')' | b'{' | b'"' | b']' | b'\r' | b'\n' => break,
+                b'[' if self.pos != start => {
+                    while try_opt!(self.peek()) != b']' {
+                        self.pos += 1;
+                    }
+                    self.pos += 1;
+                    if self.peek() == Some(b'<') {
+                        while try_opt!(self.peek()) != b'>' {
+                            self.pos += 1;
+                        }
+                        self.pos += 1;
+                    }
+                    break;
+                }
+                b'[' => break,
+                _ => self.pos += 1,
+            }
+        }
+
+        if self.pos == start {
+            return None;
+        }
+        Some(String::from_utf8_lossy(&self.input[start..self.pos]).into_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn untagged(raw: &[u8]) -> UntaggedResponse {
+        match parse(raw).unwrap() {
+            Response::Untagged(data) => data,
+            response => panic!("{:?}", response),
+        }
+    }
+
+    fn string(s: &str) -> Value {
+        Value::String(s.as_bytes().to_vec())
+    }
+
+    // The examples of section 7 of RFC 3501
+
+    #[test]
+    fn status_responses() {
+        assert_eq!(parse(b"A001 OK LOGIN completed\r\n").unwrap(),
+                   Response::Tagged("A001".to_owned(),
+                                    StatusResponse {
+                                        status: Status::Ok,
+                                        code: None,
+                                        text: "LOGIN completed".to_owned(),
+                                    }));
+        assert_eq!(untagged(b"* NO Disk is 98% full, please delete unnecessary data\r\n"),
+                   UntaggedResponse::Status(StatusResponse {
+                       status: Status::No,
+                       code: None,
+                       text: "Disk is 98% full, please delete unnecessary data".to_owned(),
+                   }));
+        assert_eq!(untagged(b"* BAD Command line too long\r\n"),
+                   UntaggedResponse::Status(StatusResponse {
+                       status: Status::Bad,
+                       code: None,
+                       text: "Command line too long".to_owned(),
+                   }));
+        assert_eq!(untagged(b"* PREAUTH IMAP4rev1 server logged in as Smith\r\n"),
+                   UntaggedResponse::Status(StatusResponse {
+                       status: Status::PreAuth,
+                       code: None,
+                       text: "IMAP4rev1 server logged in as Smith".to_owned(),
+                   }));
+        assert_eq!(untagged(b"* BYE Autologout; idle for too long\r\n"),
+                   UntaggedResponse::Status(StatusResponse {
+                       status: Status::Bye,
+                       code: None,
+                       text: "Autologout; idle for too long".to_owned(),
+                   }));
+    }
+
+    #[test]
+    fn response_codes() {
+        let code = |raw: &[u8]| {
+            match untagged(raw) {
+                UntaggedResponse::Status(status) => status.code,
+                data => panic!("{:?}", data),
+            }
+        };
+
+        assert_eq!(code(b"* OK [ALERT] System shutdown in 10 minutes\r\n"),
+                   Some(ResponseCode::Alert));
+        assert_eq!(code(b"* OK [UNSEEN 12] Message 12 is first unseen\r\n"),
+                   Some(ResponseCode::Unseen(12)));
+        assert_eq!(code(b"* OK [UIDVALIDITY 3857529045] UIDs valid\r\n"),
+                   Some(ResponseCode::UidValidity(3857529045)));
+        assert_eq!(code(b"* OK [UIDNEXT 4392] Predicted next UID\r\n"),
+                   Some(ResponseCode::UidNext(4392)));
+        assert_eq!(code(b"* OK [PERMANENTFLAGS (\\Deleted \\Seen \\*)] Limited\r\n"),
+                   Some(ResponseCode::PermanentFlags(vec!["\\Deleted".to_owned(),
+                                                          "\\Seen".to_owned(),
+                                                          "\\*".to_owned()])));
+        assert_eq!(code(b"* OK [CAPABILITY IMAP4rev1 LITERAL+] Ready\r\n"),
+                   Some(ResponseCode::Capability(vec!["IMAP4rev1".to_owned(),
+                                                      "LITERAL+".to_owned()])));
+        assert_eq!(code(b"* NO [BADCHARSET (UTF-8 \"ISO-8859-1\")] Unknown charset\r\n"),
+                   Some(ResponseCode::BadCharset(vec!["UTF-8".to_owned(),
+                                                      "ISO-8859-1".to_owned()])));
+        assert_eq!(code(b"* OK [APPENDUID 38505 3955] APPEND completed\r\n"),
+                   Some(ResponseCode::Other("APPENDUID".to_owned(),
+                                            Some("38505 3955".to_owned()))));
+    }
+
+    #[test]
+    fn continuation() {
+        assert_eq!(parse(b"+ Ready for additional command text\r\n").unwrap(),
+                   Response::Continuation(None, "Ready for additional command text".to_owned()));
+        assert_eq!(parse(b"+\r\n").unwrap(), Response::Continuation(None, String::new()));
+    }
+
+    #[test]
+    fn server_and_mailbox_status() {
+        assert_eq!(untagged(b"* CAPABILITY IMAP4rev1 STARTTLS AUTH=GSSAPI LOGINDISABLED\r\n"),
+                   UntaggedResponse::Capability(vec!["IMAP4rev1".to_owned(),
+                                                     "STARTTLS".to_owned(),
+                                                     "AUTH=GSSAPI".to_owned(),
+                                                     "LOGINDISABLED".to_owned()]));
+        assert_eq!(untagged(b"* LIST (\\Noselect) \"/\" ~/Mail/foo\r\n"),
+                   UntaggedResponse::List(MailboxList {
+                       attributes: vec!["\\Noselect".to_owned()],
+                       delimiter: Some("/".to_owned()),
+                       name: "~/Mail/foo".to_owned(),
+                   }));
+        assert_eq!(untagged(b"* LSUB () \".\" #news.comp.mail.misc\r\n"),
+                   UntaggedResponse::Lsub(MailboxList {
+                       attributes: vec![],
+                       delimiter: Some(".".to_owned()),
+                       name: "#news.comp.mail.misc".to_owned(),
+                   }));
+        assert_eq!(untagged(b"* LIST (\\Noselect) NIL \"\"\r\n"),
+                   UntaggedResponse::List(MailboxList {
+                       attributes: vec!["\\Noselect".to_owned()],
+                       delimiter: None,
+                       name: String::new(),
+                   }));
+        assert_eq!(untagged(b"* STATUS blurdybloop (MESSAGES 231 UIDNEXT 44292)\r\n"),
+                   UntaggedResponse::MailboxStatus("blurdybloop".to_owned(),
+                                                   vec![("MESSAGES".to_owned(), 231),
+                                                        ("UIDNEXT".to_owned(), 44292)]));
+        assert_eq!(untagged(b"* SEARCH 2 3 6\r\n"), UntaggedResponse::Search(vec![2, 3, 6]));
+        assert_eq!(untagged(b"* SEARCH\r\n"), UntaggedResponse::Search(vec![]));
+        assert_eq!(untagged(b"* FLAGS (\\Answered \\Flagged \\Deleted \\Seen \\Draft)\r\n"),
+                   UntaggedResponse::Flags(vec!["\\Answered".to_owned(),
+                                                "\\Flagged".to_owned(),
+                                                "\\Deleted".to_owned(),
+                                                "\\Seen".to_owned(),
+                                                "\\Draft".to_owned()]));
+    }
+
+    #[test]
+    fn mailbox_size_and_message_status() {
+        assert_eq!(untagged(b"* 23 EXISTS\r\n"), UntaggedResponse::Exists(23));
+        assert_eq!(untagged(b"* 5 RECENT\r\n"), UntaggedResponse::Recent(5));
+        assert_eq!(untagged(b"* 44 EXPUNGE\r\n"), UntaggedResponse::Expunge(44));
+        let flags = Value::List(vec![Value::Atom("\\Seen".to_owned())]);
+        assert_eq!(untagged(b"* 23 FETCH (FLAGS (\\Seen) RFC822.SIZE 44827)\r\n"),
+                   UntaggedResponse::Fetch(23,
+                                           vec![("FLAGS".to_owned(), flags),
+                                                ("RFC822.SIZE".to_owned(), Value::Number(44827))]));
+    }
+
+    #[test]
+    fn astrings_may_contain_brackets() {
+        match untagged(b"* LIST () \"/\" foo]bar\r\n") {
+            UntaggedResponse::List(list) => assert_eq!(list.name, "foo]bar"),
+            data => panic!("{:?}", data),
+        }
+        match untagged(b"* STATUS [Gmail]/Sent] (MESSAGES 2)\r\n") {
+            UntaggedResponse::MailboxStatus(name, _) => assert_eq!(name, "[Gmail]/Sent]"),
+            data => panic!("{:?}", data),
+        }
+    }
+
+    // The example session of section 8 of RFC 3501
+
+    #[test]
+    fn fetch_full() {
+        let raw = b"* 12 FETCH (FLAGS (\\Seen) INTERNALDATE \"17-Jul-1996 02:44:25 -0700\" \
+                    RFC822.SIZE 4286 ENVELOPE (\"Wed, 17 Jul 1996 02:23:25 -0700 (PDT)\" \
+                    \"IMAP4rev1 WG mtg summary and minutes\" \
+                    ((\"Terry Gray\" NIL \"gray\" \"cac.washington.edu\")) \
+                    ((\"Terry Gray\" NIL \"gray\" \"cac.washington.edu\")) \
+                    ((\"Terry Gray\" NIL \"gray\" \"cac.washington.edu\")) \
+                    ((NIL NIL \"imap\" \"cac.washington.edu\")) \
+                    ((NIL NIL \"minutes\" \"CNRI.Reston.VA.US\")\
+                    (\"John Klensin\" NIL \"KLENSIN\" \"MIT.EDU\")) NIL NIL \
+                    \"<B27397-0100000@cac.washington.edu>\") \
+                    BODY (\"TEXT\" \"PLAIN\" (\"CHARSET\" \"US-ASCII\") NIL NIL \"7BIT\" 3028 \
+                    92))\r\n";
+
+        let attributes = match untagged(raw) {
+            UntaggedResponse::Fetch(12, attributes) => attributes,
+            data => panic!("{:?}", data),
+        };
+        let names: Vec<&str> = attributes.iter().map(|&(ref name, _)| &name[..]).collect();
+        assert_eq!(names, ["FLAGS", "INTERNALDATE", "RFC822.SIZE", "ENVELOPE", "BODY"]);
+        assert_eq!(attributes[1].1, string("17-Jul-1996 02:44:25 -0700"));
+        assert_eq!(attributes[2].1, Value::Number(4286));
+
+        let envelope = attributes[3].1.as_list().unwrap();
+        assert_eq!(envelope.len(), 10);
+        assert_eq!(envelope[1], string("IMAP4rev1 WG mtg summary and minutes"));
+        assert_eq!(envelope[5].as_list().unwrap()[0],
+                   Value::List(vec![Value::Nil,
+                                    Value::Nil,
+                                    string("imap"),
+                                    string("cac.washington.edu")]));
+        assert_eq!(envelope[6].as_list().unwrap().len(), 2);
+        assert!(envelope[7].is_nil());
+
+        let body = attributes[4].1.as_list().unwrap();
+        assert_eq!(body[2], Value::List(vec![string("CHARSET"), string("US-ASCII")]));
+        assert_eq!(body[6], Value::Number(3028));
+        assert_eq!(body[7], Value::Number(92));
+    }
+
+    #[test]
+    fn fetch_literal() {
+        let mut raw = b"* 12 FETCH (BODY[HEADER] {21}\r\n".to_vec();
+        raw.extend_from_slice(b"Subject: (a) \"b\" {c}\n");
+        raw.extend_from_slice(b" FLAGS (\\Seen \\Deleted))\r\n");
+
+        let flags = Value::List(vec![Value::Atom("\\Seen".to_owned()),
+                                     Value::Atom("\\Deleted".to_owned())]);
+        assert_eq!(untagged(&raw),
+                   UntaggedResponse::Fetch(12,
+                                           vec![("BODY[HEADER]".to_owned(),
+                                                 string("Subject: (a) \"b\" {c}\n")),
+                                                ("FLAGS".to_owned(), flags)]));
+    }
+
+    #[test]
+    fn fetch_section_names() {
+        let raw = b"* 1 FETCH (BODY[HEADER.FIELDS (FROM TO)] {0}\r\n \
+                    BODY[1.2]<0> \"abc\")\r\n";
+        match untagged(raw) {
+            UntaggedResponse::Fetch(1, attributes) => {
+                assert_eq!(attributes,
+                           vec![("BODY[HEADER.FIELDS (FROM TO)]".to_owned(), string("")),
+                                ("BODY[1.2]<0>".to_owned(), string("abc"))]);
+            }
+            data => panic!("{:?}", data),
+        }
+    }
+
+    #[test]
+    fn quoted_escapes() {
+        assert_eq!(untagged(b"* LIST () \"\\\\\" \"a \\\"b\\\"\"\r\n"),
+                   UntaggedResponse::List(MailboxList {
+                       attributes: vec![],
+                       delimiter: Some("\\".to_owned()),
+                       name: "a \"b\"".to_owned(),
+                   }));
+    }
+
+    #[test]
+    fn truncated_literals_are_invalid() {
+        assert!(parse(b"* 1 FETCH (BODY[] {10}\r\nshort)\r\n").is_err());
+        assert!(parse(b"* 1 FETCH (BODY[] {18446744073709551615}\r\n)\r\n").is_err());
+    }
+}
@@ -0,0 +1,351 @@
+//! Search criteria for `SEARCH` and `UID SEARCH`, following the grammar of RFC 3501 section
+//! 6.4.4.
+
+use command::Command;
+use date::Date;
+use imaperror::IMAPError;
+use {SequenceSet, UidSet};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Key {
+    /// A key without arguments, such as `SEEN`.
+    Bare(&'static str),
+    /// A key taking a string, such as `FROM`.
+    Text(&'static str, Vec<u8>),
+    Header(Vec<u8>, Vec<u8>),
+    Date(&'static str, Date),
+    Number(&'static str, u32),
+    Sequence(SequenceSet),
+    Uid(UidSet),
+    Not(Box<Key>),
+    Or(Box<Key>, Box<Key>),
+    Group(Vec<Key>),
+}
+
+impl Key {
+    fn is_ascii(&self) -> bool {
+        match *self {
+            Key::Text(_, ref value) => value.iter().all(|&b| b < 0x80),
+            Key::Header(ref field, ref value) => field.iter().chain(value).all(|&b| b < 0x80),
+            Key::Not(ref key) => key.is_ascii(),
+            Key::Or(ref a, ref b) => a.is_ascii() && b.is_ascii(),
+            Key::Group(ref keys) => keys.iter().all(Key::is_ascii),
+            _ => true,
+        }
+    }
+
+    /// Fails if the key matches no message set: a sequence or UID set that is empty.
+    fn check(&self) -> Result<(), IMAPError> {
+        match *self {
+            Key::Sequence(ref set) if set.is_empty() => {
+                Err(IMAPError::Invalid("Can not SEARCH an empty sequence set".to_owned()))
+            }
+            Key::Uid(ref set) if set.is_empty() => {
+                Err(IMAPError::Invalid("Can not SEARCH an empty UID set".to_owned()))
+            }
+            Key::Not(ref key) => key.check(),
+            Key::Or(ref a, ref b) => a.check().and_then(|_| b.check()),
+            Key::Group(ref keys) => {
+                for key in keys {
+                    try!(key.check());
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn write(&self, cmd: Command) -> Command {
+        match *self {
+            Key::Bare(name) => cmd.raw(name),
+            Key::Text(name, ref value) => cmd.raw(name).arg(value),
+            Key::Header(ref field, ref value) => cmd.raw("HEADER").arg(field).arg(value),
+            Key::Date(name, date) => cmd.raw(name).raw(&date.to_string()),
+            Key::Number(name, n) => cmd.raw(name).raw(&n.to_string()),
+            Key::Sequence(ref set) => cmd.raw(&set.to_string()),
+            Key::Uid(ref set) => cmd.raw("UID").raw(&set.to_string()),
+            Key::Not(ref key) => key.write(cmd.raw("NOT")),
+            Key::Or(ref a, ref b) => b.write(a.write(cmd.raw("OR"))),
+            Key::Group(ref keys) => {
+                let mut cmd = cmd.open_list();
+                for key in keys {
+                    cmd = key.write(cmd);
+                }
+                cmd.close_list()
+            }
+        }
+    }
+}
+
+/// A set of search keys that a message has to match all of.
+///
+/// Keys are added with the builder methods, each narrowing the search further:
+///
+/// ```ignore
+/// let query = SearchQuery::new()
+///                 .from("alice@example.org")
+///                 .since(Date::new(2016, 5, 1).unwrap())
+///                 .unseen();
+/// ```
+///
+/// An empty query matches every message.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SearchQuery {
+    keys: Vec<Key>,
+}
+
+impl SearchQuery {
+    pub fn new() -> SearchQuery {
+        SearchQuery { keys: Vec::new() }
+    }
+
+    fn key(mut self, key: Key) -> SearchQuery {
+        self.keys.push(key);
+        self
+    }
+
+    fn text<S: AsRef<str>>(self, name: &'static str, value: S) -> SearchQuery {
+        self.key(Key::Text(name, value.as_ref().as_bytes().to_vec()))
+    }
+
+    /// The keys of this query as a single key, parenthesised if there is more than one.
+    fn into_key(mut self) -> Key {
+        match self.keys.len() {
+            0 => Key::Bare("ALL"),
+            1 => self.keys.pop().unwrap(),
+            _ => Key::Group(self.keys),
+        }
+    }
+
+    /// Matches every message.
+    pub fn all(self) -> SearchQuery {
+        self.key(Key::Bare("ALL"))
+    }
+
+    pub fn answered(self) -> SearchQuery {
+        self.key(Key::Bare("ANSWERED"))
+    }
+
+    pub fn unanswered(self) -> SearchQuery {
+        self.key(Key::Bare("UNANSWERED"))
+    }
+
+    pub fn deleted(self) -> SearchQuery {
+        self.key(Key::Bare("DELETED"))
+    }
+
+    pub fn undeleted(self) -> SearchQuery {
+        self.key(Key::Bare("UNDELETED"))
+    }
+
+    pub fn draft(self) -> SearchQuery {
+        self.key(Key::Bare("DRAFT"))
+    }
+
+    pub fn undraft(self) -> SearchQuery {
+        self.key(Key::Bare("UNDRAFT"))
+    }
+
+    pub fn flagged(self) -> SearchQuery {
+        self.key(Key::Bare("FLAGGED"))
+    }
+
+    pub fn unflagged(self) -> SearchQuery {
+        self.key(Key::Bare("UNFLAGGED"))
+    }
+
+    pub fn seen(self) -> SearchQuery {
+        self.key(Key::Bare("SEEN"))
+    }
+
+    pub fn unseen(self) -> SearchQuery {
+        self.key(Key::Bare("UNSEEN"))
+    }
+
+    pub fn recent(self) -> SearchQuery {
+        self.key(Key::Bare("RECENT"))
+    }
+
+    /// Messages that are `\Recent` but not `\Seen` (the `NEW` key).
+    pub fn new_messages(self) -> SearchQuery {
+        self.key(Key::Bare("NEW"))
+    }
+
+    /// Messages that are not `\Recent` (the `OLD` key).
+    pub fn old(self) -> SearchQuery {
+        self.key(Key::Bare("OLD"))
+    }
+
+    /// Messages with the keyword flag `keyword` set.
+    pub fn keyword<S: AsRef<str>>(self, keyword: S) -> SearchQuery {
+        self.text("KEYWORD", keyword)
+    }
+
+    pub fn unkeyword<S: AsRef<str>>(self, keyword: S) -> SearchQuery {
+        self.text("UNKEYWORD", keyword)
+    }
+
+    pub fn from<S: AsRef<str>>(self, value: S) -> SearchQuery {
+        self.text("FROM", value)
+    }
+
+    pub fn to<S: AsRef<str>>(self, value: S) -> SearchQuery {
+        self.text("TO", value)
+    }
+
+    pub fn cc<S: AsRef<str>>(self, value: S) -> SearchQuery {
+        self.text("CC", value)
+    }
+
+    pub fn bcc<S: AsRef<str>>(self, value: S) -> SearchQuery {
+        self.text("BCC", value)
+    }
+
+    pub fn subject<S: AsRef<str>>(self, value: S) -> SearchQuery {
+        self.text("SUBJECT", value)
+    }
+
+    /// Messages whose body contains `value`.
+    pub fn body<S: AsRef<str>>(self, value: S) -> SearchQuery {
+        self.text("BODY", value)
+    }
+
+    /// Messages whose header or body contains `value`.
+    pub fn text_contains<S: AsRef<str>>(self, value: S) -> SearchQuery {
+        self.text("TEXT", value)
+    }
+
+    /// Messages with a header `field` that contains `value`. An empty `value` matches every
+    /// message that has the header at all.
+    pub fn header<F: AsRef<str>, S: AsRef<str>>(self, field: F, value: S) -> SearchQuery {
+        self.key(Key::Header(field.as_ref().as_bytes().to_vec(),
+                             value.as_ref().as_bytes().to_vec()))
+    }
+
+    /// Messages whose internal date is earlier than `date`.
+    pub fn before(self, date: Date) -> SearchQuery {
+        self.key(Key::Date("BEFORE", date))
+    }
+
+    /// Messages whose internal date is `date`.
+    pub fn on(self, date: Date) -> SearchQuery {
+        self.key(Key::Date("ON", date))
+    }
+
+    /// Messages whose internal date is `date` or later.
+    pub fn since(self, date: Date) -> SearchQuery {
+        self.key(Key::Date("SINCE", date))
+    }
+
+    /// Like `before`, but compares the `Date:` header instead.
+    pub fn sent_before(self, date: Date) -> SearchQuery {
+        self.key(Key::Date("SENTBEFORE", date))
+    }
+
+    pub fn sent_on(self, date: Date) -> SearchQuery {
+        self.key(Key::Date("SENTON", date))
+    }
+
+    pub fn sent_since(self, date: Date) -> SearchQuery {
+        self.key(Key::Date("SENTSINCE", date))
+    }
+
+    /// Messages larger than `size` octets.
+    pub fn larger(self, size: u32) -> SearchQuery {
+        self.key(Key::Number("LARGER", size))
+    }
+
+    /// Messages smaller than `size` octets.
+    pub fn smaller(self, size: u32) -> SearchQuery {
+        self.key(Key::Number("SMALLER", size))
+    }
+
+    /// Messages with a sequence number in `set`.
+    pub fn sequence<T: Into<SequenceSet>>(self, set: T) -> SearchQuery {
+        self.key(Key::Sequence(set.into()))
+    }
+
+    /// Messages with a UID in `set`.
+    pub fn uid<T: Into<UidSet>>(self, set: T) -> SearchQuery {
+        self.key(Key::Uid(set.into()))
+    }
+
+    /// Messages that do not match all of `query`.
+    pub fn not(self, query: SearchQuery) -> SearchQuery {
+        self.key(Key::Not(Box::new(query.into_key())))
+    }
+
+    /// Messages that match all of `a` or all of `b`.
+    pub fn or(self, a: SearchQuery, b: SearchQuery) -> SearchQuery {
+        self.key(Key::Or(Box::new(a.into_key()), Box::new(b.into_key())))
+    }
+
+    /// Messages that match all of `query`, which is sent as a parenthesised group.
+    pub fn and(self, query: SearchQuery) -> SearchQuery {
+        if query.keys.is_empty() {
+            self.all()
+        } else {
+            self.key(Key::Group(query.keys))
+        }
+    }
+}
+
+impl Command {
+    /// Appends the keys of `query`, preceded by `CHARSET UTF-8` if any of them are not ASCII.
+    /// Fails if the query has an empty sequence or UID set.
+    pub fn search(self, query: &SearchQuery) -> Result<Command, IMAPError> {
+        for key in &query.keys {
+            try!(key.check());
+        }
+
+        let mut cmd = if query.keys.iter().all(Key::is_ascii) {
+            self
+        } else {
+            self.raw("CHARSET UTF-8")
+        };
+
+        if query.keys.is_empty() {
+            return Ok(cmd.raw("ALL"));
+        }
+        for key in &query.keys {
+            cmd = key.write(cmd);
+        }
+        Ok(cmd)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use command::{Command, Part};
+    use UidSet;
+    use super::SearchQuery;
+
+    #[test]
+    fn query() {
+        let query = SearchQuery::new()
+                        .unseen()
+                        .sequence((2, 4))
+                        .not(SearchQuery::new().uid(UidSet::single(7)))
+                        .or(SearchQuery::new().larger(100), SearchQuery::new().subject("hi"));
+        let cmd = Command::new("SEARCH").search(&query).unwrap();
+        assert_eq!(cmd.parts(),
+                   [Part::Text(b"SEARCH UNSEEN 2:4 NOT UID 7 OR LARGER 100 SUBJECT hi".to_vec())]);
+
+        let cmd = Command::new("SEARCH").search(&SearchQuery::new()).unwrap();
+        assert_eq!(cmd.parts(), [Part::Text(b"SEARCH ALL".to_vec())]);
+    }
+
+    #[test]
+    fn empty_sets_are_refused() {
+        let no_uids = UidSet::from_uids(Vec::new());
+        let queries = [SearchQuery::new().sequence(Vec::new()),
+                       SearchQuery::new().uid(no_uids.clone()),
+                       SearchQuery::new().not(SearchQuery::new().sequence(Vec::new())),
+                       SearchQuery::new().or(SearchQuery::new().seen(),
+                                             SearchQuery::new().uid(no_uids)),
+                       SearchQuery::new().and(SearchQuery::new().seen().sequence(Vec::new()))];
+        for query in &queries {
+            assert!(Command::new("SEARCH").search(query).is_err(), "{:?}", query);
+        }
+    }
+}
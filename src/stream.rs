@@ -0,0 +1,403 @@
+use capability::Capabilities;
+use command::{Command, Part};
+use imaperror::IMAPError;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::time::Duration;
+use IMAPConnection;
+
+/// A buffered wrapper around an `IMAPConnection` that knows how IMAP responses are framed.
+///
+/// Rather than reading until the socket times out, a command's response is read line by line
+/// until the tagged completion line for that command arrives.
+#[derive(Debug)]
+pub struct Stream {
+    reader: BufReader<IMAPConnection>,
+    capabilities: Option<Capabilities>,
+    /// The read timeout the connection was given, to go back to after waiting with another.
+    read_timeout: Option<Duration>,
+}
+
+impl Stream {
+    pub fn new(imap: IMAPConnection) -> Stream {
+        let read_timeout = imap.read_timeout().unwrap_or(None);
+        Stream {
+            reader: BufReader::new(imap),
+            capabilities: None,
+            read_timeout: read_timeout,
+        }
+    }
+
+    /// The capabilities last announced by the server, if they are still valid.
+    pub fn capabilities(&self) -> Option<&Capabilities> {
+        self.capabilities.as_ref()
+    }
+
+    pub fn set_capabilities(&mut self, capabilities: Option<Capabilities>) {
+        self.capabilities = capabilities;
+    }
+
+    /// Switches the connection to TLS with the server `domain`, after the server has accepted
+    /// `STARTTLS`.
+    ///
+    /// Anything the server sent before the handshake would have been injected by an attacker,
+    /// so it is an error for any data to have been read ahead. The cached capabilities are
+    /// dropped, as they must be asked for again over the encrypted connection.
+    pub fn upgrade_tls(&mut self, domain: &str) -> Result<(), IMAPError> {
+        if !self.reader.buffer().is_empty() {
+            self.reader = BufReader::new(IMAPConnection::Disconnected);
+            return Err(IMAPError::StartTlsError("Server sent unexpected data before the TLS \
+                                                 handshake"
+                                                    .to_owned()));
+        }
+
+        self.capabilities = None;
+        self.reader.get_mut().upgrade_tls(domain)
+    }
+
+    /// Reads the single line greeting the server sends when the connection is opened.
+    pub fn read_greeting(&mut self) -> Result<Vec<u8>, IMAPError> {
+        let mut line = Vec::new();
+        try!(self.read_line(&mut line));
+        Ok(line)
+    }
+
+    /// Sends `cmd` tagged with `tag` and reads the response up to and including the tagged
+    /// completion line for `tag`.
+    ///
+    /// Literals in `cmd` are only sent once the server has asked for them with a continuation
+    /// request. If it refuses one instead, the rest of the command is not sent.
+    ///
+    /// Each element of the returned `Vec` is one complete server response, including the
+    /// contents of any literals it contained. Responses are kept as bytes, since message data is
+    /// not necessarily valid UTF-8.
+    pub fn command(&mut self, tag: &str, cmd: &Command) -> Result<Vec<Vec<u8>>, IMAPError> {
+        let mut responses = Vec::new();
+        if try!(self.send(tag, cmd, &mut responses)) {
+            responses.extend(try!(self.read_response(tag)));
+        }
+        Ok(responses)
+    }
+
+    /// Sends `cmd`, then reads until the server either asks for a continuation, in which case
+    /// `true` is returned, or completes the command, in which case `false` is returned. Any other
+    /// responses read are added to `responses`.
+    pub fn begin(&mut self,
+                 tag: &str,
+                 cmd: &Command,
+                 responses: &mut Vec<Vec<u8>>)
+                 -> Result<bool, IMAPError> {
+        if !try!(self.send(tag, cmd, responses)) {
+            return Ok(false);
+        }
+        self.wait_for_continuation(tag, responses)
+    }
+
+    /// Sends `line` to end a command started with `begin`, then reads the rest of its response.
+    pub fn finish(&mut self, tag: &str, line: &[u8]) -> Result<Vec<Vec<u8>>, IMAPError> {
+        try!(self.write(line));
+        self.read_response(tag)
+    }
+
+    /// Sets how long a read waits for data before failing, until `restore_read_timeout` is
+    /// called. `None` waits indefinitely.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<(), IMAPError> {
+        try!(self.reader.get_ref().set_read_timeout(timeout));
+        Ok(())
+    }
+
+    /// Goes back to the read timeout the connection was given.
+    pub fn restore_read_timeout(&mut self) -> Result<(), IMAPError> {
+        let timeout = self.read_timeout;
+        self.set_read_timeout(timeout)
+    }
+
+    /// Reads one response like `read_single_response`, unless the read timeout passes before its
+    /// first line is complete. In that case `None` is returned and what was read so far is kept
+    /// in `partial`, to be continued by the next call.
+    pub fn poll_response(&mut self, partial: &mut Vec<u8>) -> Result<Option<Vec<u8>>, IMAPError> {
+        match self.reader.read_until(b'\n', partial) {
+            Ok(0) => return Err(IMAPError::ConnectError("Connection closed by server".to_owned())),
+            Ok(_) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                          e.kind() == io::ErrorKind::TimedOut => return Ok(None),
+            Err(e) => return Err(IMAPError::IOError(e)),
+        }
+        if !partial.ends_with(b"\n") {
+            return Ok(None);
+        }
+
+        let line = partial.split_off(0);
+        if literal_length(&line).is_none() {
+            return Ok(Some(line));
+        }
+
+        // The rest of the response follows immediately, so finish it as any other
+        try!(self.restore_read_timeout());
+        self.read_rest_of_response(line).map(Some)
+    }
+
+    /// Whether a complete line has already been read from the connection but not yet returned.
+    pub fn has_buffered_line(&self) -> bool {
+        self.reader.buffer().contains(&b'\n')
+    }
+
+    /// Sends `cmd` like `command`, then answers each continuation request the server sends with
+    /// the line `respond` returns for it, until the command completes.
+    ///
+    /// This is the exchange used by `AUTHENTICATE`: if `respond` fails, the exchange is cancelled
+    /// by sending `*` and its error is returned once the server has completed the command.
+    pub fn interact<F>(&mut self,
+                       tag: &str,
+                       cmd: &Command,
+                       mut respond: F)
+                       -> Result<Vec<Vec<u8>>, IMAPError>
+        where F: FnMut(&[u8]) -> Result<Vec<u8>, IMAPError>
+    {
+        let mut responses = Vec::new();
+        if !try!(self.send(tag, cmd, &mut responses)) {
+            return Ok(responses);
+        }
+
+        let prefix = tag.to_owned() + " ";
+        loop {
+            let response = try!(self.read_single_response());
+
+            if response.starts_with(b"+") {
+                match respond(&response) {
+                    Ok(mut line) => {
+                        line.extend_from_slice(b"\r\n");
+                        try!(self.write(&line));
+                    }
+                    Err(e) => {
+                        try!(self.write(b"*\r\n"));
+                        try!(self.read_response(tag));
+                        return Err(e);
+                    }
+                }
+                continue;
+            }
+
+            let done = response.starts_with(prefix.as_bytes());
+            responses.push(response);
+
+            if done {
+                return Ok(responses);
+            }
+        }
+    }
+
+    /// Writes `cmd`, sending each of its literals once the server asks for it.
+    ///
+    /// Returns `false` if the server completed the command rather than accepting a literal, in
+    /// which case its responses have been added to `responses`.
+    pub fn send(&mut self,
+                tag: &str,
+                cmd: &Command,
+                responses: &mut Vec<Vec<u8>>)
+                -> Result<bool, IMAPError> {
+        let mut line = format!("{} ", tag).into_bytes();
+
+        for part in cmd.parts() {
+            match *part {
+                Part::Text(ref text) => line.extend_from_slice(text),
+                Part::Literal(ref literal) => {
+                    line.extend_from_slice(format!("{{{}}}\r\n", literal.len()).as_bytes());
+                    try!(self.write(&line));
+                    if !try!(self.wait_for_continuation(tag, responses)) {
+                        return Ok(false);
+                    }
+                    line = literal.to_owned();
+                }
+            }
+        }
+
+        line.extend_from_slice(b"\r\n");
+        try!(self.write(&line));
+        Ok(true)
+    }
+
+    /// Reads responses until the server sends a continuation request, in which case `true` is
+    /// returned, or completes the command tagged `tag`, in which case `false` is returned.
+    ///
+    /// Everything but the continuation request itself is added to `responses`.
+    fn wait_for_continuation(&mut self,
+                             tag: &str,
+                             responses: &mut Vec<Vec<u8>>)
+                             -> Result<bool, IMAPError> {
+        let tag = tag.to_owned() + " ";
+
+        loop {
+            let response = try!(self.read_single_response());
+            if response.starts_with(b"+") {
+                return Ok(true);
+            }
+
+            let done = response.starts_with(tag.as_bytes());
+            responses.push(response);
+
+            if done {
+                return Ok(false);
+            }
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<(), IMAPError> {
+        let stream = self.reader.get_mut();
+        try!(stream.write_all(buf));
+        try!(stream.flush());
+        Ok(())
+    }
+
+    /// Reads responses until one begins with `tag`, returning everything read.
+    fn read_response(&mut self, tag: &str) -> Result<Vec<Vec<u8>>, IMAPError> {
+        let tag = tag.to_owned() + " ";
+        let mut responses = Vec::new();
+
+        loop {
+            let response = try!(self.read_single_response());
+            let done = response.starts_with(tag.as_bytes());
+            responses.push(response);
+
+            if done {
+                return Ok(responses);
+            }
+        }
+    }
+
+    /// Reads one response. A line ending in a literal marker `{n}` is followed by exactly `n`
+    /// octets and then the remainder of the response, so those are read as part of it too.
+    fn read_single_response(&mut self) -> Result<Vec<u8>, IMAPError> {
+        let mut line = Vec::new();
+        try!(self.read_line(&mut line));
+        self.read_rest_of_response(line)
+    }
+
+    /// Completes a response of which `line` is the first line.
+    pub fn read_rest_of_response(&mut self, mut line: Vec<u8>) -> Result<Vec<u8>, IMAPError> {
+        let mut response = Vec::new();
+
+        loop {
+            response.extend_from_slice(&line);
+
+            match literal_length(&line) {
+                Some(len) => try!(self.read_literal_to_end(len, &mut response)),
+                None => return Ok(response),
+            }
+
+            line.clear();
+            try!(self.read_line(&mut line));
+        }
+    }
+
+    /// Reads a literal of `len` octets onto the end of `buf`.
+    ///
+    /// The length comes from the server, so memory is only taken for octets as they arrive,
+    /// rather than for however many were announced.
+    pub fn read_literal_to_end(&mut self, len: usize, buf: &mut Vec<u8>) -> Result<(), IMAPError> {
+        let read = try!((&mut self.reader).take(len as u64).read_to_end(buf));
+        if read < len {
+            return Err(IMAPError::ConnectError("Connection closed by server".to_owned()));
+        }
+        Ok(())
+    }
+
+    /// Reads up to `buf.len()` octets of a literal whose length has been read already.
+    pub fn read_literal(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+
+    pub fn read_line(&mut self, line: &mut Vec<u8>) -> Result<(), IMAPError> {
+        let read = try!(self.reader.read_until(b'\n', line));
+        if read == 0 {
+            return Err(IMAPError::ConnectError("Connection closed by server".to_owned()));
+        }
+        Ok(())
+    }
+}
+
+/// Returns the length of the literal announced at the end of `line`, if there is one.
+pub fn literal_length(line: &[u8]) -> Option<usize> {
+    if !line.ends_with(b"}\r\n") {
+        return None;
+    }
+
+    let line = &line[..line.len() - 3];
+    match line.iter().rposition(|&b| b == b'{') {
+        Some(index) => String::from_utf8_lossy(&line[index + 1..]).parse().ok(),
+        None => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use command::Command;
+    use imaperror::IMAPError;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+    use super::Stream;
+    use IMAPConnection;
+
+    /// A stream whose server answers the first command with `data` and closes the connection.
+    fn stream(data: &'static [u8]) -> Stream {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut command = String::new();
+            BufReader::new(stream.try_clone().unwrap()).read_line(&mut command).unwrap();
+            stream.write_all(data).unwrap();
+        });
+        Stream::new(IMAPConnection::Basic(TcpStream::connect(addr).unwrap()))
+    }
+
+    #[test]
+    fn reads_literals() {
+        let mut stream = stream(b"* 1 FETCH (BODY[] {5}\r\nHello)\r\na1 OK done\r\n");
+        let responses = stream.command("a1", &Command::new("NOOP")).unwrap();
+        assert_eq!(responses,
+                   vec![b"* 1 FETCH (BODY[] {5}\r\nHello)\r\n".to_vec(), b"a1 OK done\r\n".to_vec()]);
+    }
+
+    #[test]
+    fn huge_literal_length_is_not_allocated() {
+        let mut stream = stream(b"* 1 FETCH (BODY[] {18446744073709551615}\r\nshort");
+        match stream.command("a1", &Command::new("NOOP")) {
+            Err(IMAPError::ConnectError(_)) => {}
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn literals_wait_for_continuation() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut announced = String::new();
+            reader.read_line(&mut announced).unwrap();
+
+            // Nothing more may be sent before the server asks for the literal
+            reader.get_ref().set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+            let mut early = Vec::new();
+            assert!(reader.read_until(b'\n', &mut early).is_err());
+            assert!(early.is_empty());
+            reader.get_ref().set_read_timeout(None).unwrap();
+
+            writer.write_all(b"+ go ahead\r\n").unwrap();
+            let mut rest = String::new();
+            reader.read_line(&mut rest).unwrap();
+            writer.write_all(b"a1 OK done\r\n").unwrap();
+            (announced, rest)
+        });
+
+        let mut stream = Stream::new(IMAPConnection::Basic(TcpStream::connect(addr).unwrap()));
+        let cmd = Command::new("LOGIN").arg("caf\u{e9}").arg("secret");
+        assert_eq!(stream.command("a1", &cmd).unwrap(), vec![b"a1 OK done\r\n".to_vec()]);
+        assert_eq!(server.join().unwrap(),
+                   ("a1 LOGIN {5}\r\n".to_owned(), "caf\u{e9} secret\r\n".to_owned()));
+    }
+}
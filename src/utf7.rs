@@ -0,0 +1,131 @@
+//! The modified UTF-7 encoding of international mailbox names, from RFC 3501 section 5.1.3.
+
+const ALPHABET: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+,";
+
+/// Encodes a mailbox name for use in a command.
+pub fn encode(name: &str) -> String {
+    let mut output = String::with_capacity(name.len());
+    let mut pending: Vec<u16> = Vec::new();
+
+    for c in name.chars() {
+        if c >= ' ' && c <= '~' {
+            flush(&mut pending, &mut output);
+            if c == '&' {
+                output.push_str("&-");
+            } else {
+                output.push(c);
+            }
+        } else {
+            let mut buf = [0; 2];
+            let len = encode_utf16(c, &mut buf);
+            pending.extend_from_slice(&buf[..len]);
+        }
+    }
+    flush(&mut pending, &mut output);
+
+    output
+}
+
+/// Decodes a mailbox name sent by the server, returning `None` if it is not valid modified UTF-7.
+pub fn decode(name: &str) -> Option<String> {
+    let mut output = String::with_capacity(name.len());
+    let mut chars = name.split('&');
+
+    output.push_str(try_opt!(chars.next()));
+
+    for part in chars {
+        let end = try_opt!(part.find('-'));
+        let (encoded, rest) = (&part[..end], &part[end + 1..]);
+
+        if encoded.is_empty() {
+            output.push('&');
+        } else {
+            let mut bits = 0u32;
+            let mut nbits = 0;
+            let mut units = Vec::new();
+
+            for b in encoded.bytes() {
+                let value = try_opt!(ALPHABET.iter().position(|&a| a == b));
+                bits = bits << 6 | value as u32;
+                nbits += 6;
+                if nbits >= 16 {
+                    nbits -= 16;
+                    units.push((bits >> nbits) as u16);
+                    bits &= (1 << nbits) - 1;
+                }
+            }
+
+            output.push_str(&try_opt!(String::from_utf16(&units).ok()));
+        }
+
+        output.push_str(rest);
+    }
+
+    Some(output)
+}
+
+/// Writes out the UTF-16 code units in `pending` as a base64 run, if there are any.
+fn flush(pending: &mut Vec<u16>, output: &mut String) {
+    if pending.is_empty() {
+        return;
+    }
+
+    let mut bits = 0u32;
+    let mut nbits = 0;
+
+    output.push('&');
+    for unit in pending.drain(..) {
+        bits = bits << 16 | unit as u32;
+        nbits += 16;
+        while nbits >= 6 {
+            nbits -= 6;
+            output.push(ALPHABET[(bits >> nbits) as usize & 63] as char);
+        }
+        bits &= (1 << nbits) - 1;
+    }
+    if nbits > 0 {
+        output.push(ALPHABET[(bits << (6 - nbits)) as usize & 63] as char);
+    }
+    output.push('-');
+}
+
+fn encode_utf16(c: char, buf: &mut [u16; 2]) -> usize {
+    let c = c as u32;
+    if c < 0x10000 {
+        buf[0] = c as u16;
+        1
+    } else {
+        let c = c - 0x10000;
+        buf[0] = 0xd800 | (c >> 10) as u16;
+        buf[1] = 0xdc00 | (c & 0x3ff) as u16;
+        2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode};
+
+    #[test]
+    fn round_trip() {
+        // The example of section 5.1.3 of RFC 3501
+        let name = "~peter/mail/\u{53f0}\u{5317}/\u{65e5}\u{672c}\u{8a9e}";
+        assert_eq!(encode(name), "~peter/mail/&U,BTFw-/&ZeVnLIqe-");
+        assert_eq!(decode("~peter/mail/&U,BTFw-/&ZeVnLIqe-").unwrap(), name);
+
+        for &(name, encoded) in &[("INBOX", "INBOX"),
+                                  ("Tom & Jerry", "Tom &- Jerry"),
+                                  ("Entw\u{fc}rfe", "Entw&APw-rfe"),
+                                  ("\u{1f600}", "&2D3eAA-")] {
+            assert_eq!(encode(name), encoded);
+            assert_eq!(decode(encoded).unwrap(), name);
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        assert_eq!(decode("&U,BTFw"), None);
+        assert_eq!(decode("&U!BTFw-"), None);
+        assert_eq!(decode("&2D0-"), None);
+    }
+}